@@ -0,0 +1,21 @@
+//! Compares `is_match` with `search` on lines where the match comes early, which is where stopping
+//! at the first match pays off. Run with `cargo bench --bench is_match`.
+
+use std::time::Instant;
+
+use regex_demo::{interpreter, regex};
+
+fn main() {
+    let tail = "z".repeat(1 << 20);
+    for (pattern, line) in [("ab+c", format!("abbbc{tail}")), ("(\\w+)@(\\w+)", format!("me@example {tail}"))] {
+        let prog = regex::compile(pattern).unwrap();
+
+        let start = Instant::now();
+        let result = interpreter::search(&prog, &line);
+        println!("search for {pattern} on a 1MB line: {result:?} in {:?}", start.elapsed());
+
+        let start = Instant::now();
+        let result = interpreter::is_match(&prog, &line);
+        println!("is_match for {pattern} on a 1MB line: {result:?} in {:?}", start.elapsed());
+    }
+}
@@ -0,0 +1,28 @@
+//! Times a long literal pattern compiled with `Byte` instructions against the same program with
+//! each `Byte` written as the single-byte `Branch` range it replaced. Run with
+//! `cargo bench --bench literal`.
+
+use std::time::Instant;
+
+use regex_demo::{interpreter, regex};
+use regex_demo::regex::Instruction;
+
+fn main() {
+    let prog = regex::compile("the quick brown fox jumps over the lazy dog").unwrap();
+    let ranges: Vec<Instruction> = prog.iter()
+        .map(|inst| match *inst {
+            Instruction::Byte(c) => {
+                Instruction::Branch{c_min: c, c_max: c, dest: 0, consume: true, inverted: false}
+            }
+            inst => inst,
+        })
+        .collect();
+    // Plenty of near misses, so that the threads get a fair way into the literal before failing.
+    let text = "the quick brown fox jumps over the lazy cat. ".repeat(20_000);
+
+    for (name, prog) in [("Byte", &prog), ("Branch", &ranges)] {
+        let start = Instant::now();
+        let result = interpreter::search(prog, &text);
+        println!("{name} on {}KB: {result:?} in {:?}", text.len() / 1024, start.elapsed());
+    }
+}
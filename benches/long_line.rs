@@ -0,0 +1,33 @@
+//! Times searches over a single 1MB line, which used to take time quadratic in the length of the
+//! line because every offset kept its own thread alive. Run with `cargo bench --bench long_line`.
+
+use std::time::Instant;
+
+use regex_demo::{interpreter, regex};
+
+fn main() {
+    let prog = regex::compile("x+y").unwrap();
+    let line = "x".repeat(1 << 20);
+
+    let start = Instant::now();
+    let result = interpreter::search(&prog, &line);
+    println!("search on a 1MB line: {result:?} in {:?}", start.elapsed());
+
+    let start = Instant::now();
+    let result = interpreter::is_match(&prog, &line);
+    println!("is_match on a 1MB line: {result:?} in {:?}", start.elapsed());
+
+    let start = Instant::now();
+    let result = interpreter::search_last(&prog, &line);
+    println!("search_last on a 1MB line: {result:?} in {:?}", start.elapsed());
+
+    // `search_overlapping` still keeps a thread for every start, which is what `search` used to do.
+    // That's far too slow for the whole line, so it only gets a small piece of it for comparison.
+    let piece = &line[..4000];
+    let start = Instant::now();
+    interpreter::search_overlapping(&prog, piece);
+    println!("every start on a 4KB line: {:?}", start.elapsed());
+    let start = Instant::now();
+    interpreter::search(&prog, piece);
+    println!("search on a 4KB line: {:?}", start.elapsed());
+}
@@ -0,0 +1,31 @@
+//! Times searching a log-like file with and without skipping the lines that are missing the
+//! pattern's required literal, like the CLI does. Run with `cargo bench --bench prefilter`.
+
+use std::time::Instant;
+
+use regex_demo::{interpreter, regex};
+use regex_demo::regex::analyze;
+
+fn main() {
+    // One line in a hundred has the literal in it.
+    let lines: Vec<String> = (0..100_000)
+        .map(|n| if n % 100 == 0 {
+            format!("{n:06} error: job {} failed after a retry", n / 100)
+        } else {
+            format!("{n:06} info: job {} finished without any problems", n / 100)
+        })
+        .collect();
+    let prog = regex::compile("error: job (\\d+) failed").unwrap();
+    let literal = analyze::required_literal(&prog).unwrap();
+
+    let start = Instant::now();
+    let count = lines.iter().filter(|line| interpreter::search(&prog, line).is_some()).count();
+    println!("search on every line: {count} lines in {:?}", start.elapsed());
+
+    let start = Instant::now();
+    let count = lines.iter()
+        .filter(|line| analyze::contains(line.as_bytes(), &literal))
+        .filter(|line| interpreter::search(&prog, line).is_some())
+        .count();
+    println!("search after the literal check: {count} lines in {:?}", start.elapsed());
+}
@@ -0,0 +1,102 @@
+use alloc::string::String;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::error::Error;
+#[cfg(feature = "std")]
+use std::io;
+
+use crate::regex::validate::Lint;
+
+/// Everything that can go wrong while loading or running a regex program.
+#[derive(Debug)]
+pub enum RegexError {
+    #[cfg(feature = "std")]
+    Io(io::Error),
+    /// The input ended partway through an instruction starting at byte `offset`.
+    TruncatedInstruction { offset: usize },
+    UnknownOpcode { opcode: u32, instruction: u32 },
+    /// The program's header is for a version of the bytecode format that isn't supported.
+    UnsupportedVersion { version: u8 },
+    /// The checksum in the program's header doesn't match its instructions, so the file has been
+    /// corrupted.
+    ChecksumMismatch { expected: u32, actual: u32 },
+    /// The program doesn't have any instructions.
+    EmptyProgram,
+    /// The instruction at `pc` jumps to `dest`, which is outside of the program.
+    InvalidDestination { pc: usize, dest: usize },
+    /// The instruction at `pc` jumps to `dest`, which is too far away to fit in the bytecode.
+    DestinationOverflow { pc: usize, dest: usize },
+    /// The instruction at `pc` can't be written in the version of the bytecode being encoded.
+    UnsupportedInstruction { pc: usize },
+    /// The instruction at `pc` uses a save slot (or group) that is too big to fit in the bytecode.
+    SaveIndexOverflow { pc: usize, index: usize },
+    /// The pattern could not be compiled because of a problem at byte `pos`.
+    InvalidPattern { pos: usize, message: String },
+    /// The search ran more than `steps_per_char` instructions for each byte of input.
+    ComplexityLimit { steps_per_char: u64 },
+    /// The search needed more than `limit` threads to run at once.
+    ThreadLimitExceeded { limit: usize },
+    /// The search ran for longer than its timeout.
+    Timeout,
+    /// A thread can loop back to the instruction at `pc` without consuming any input.
+    EpsilonCycle { pc: usize },
+    /// Line `line` of an assembly listing couldn't be turned into an instruction.
+    InvalidAssembly { line: usize, message: String },
+    /// A lint that was treated as an error because of strict checking.
+    Lint(Lint),
+}
+
+impl fmt::Display for RegexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(feature = "std")]
+            RegexError::Io(err) => write!(f, "I/O error: {err}"),
+            RegexError::TruncatedInstruction { offset } => write!(
+                f, "Truncated instruction at byte offset {offset}"),
+            RegexError::UnknownOpcode { opcode, instruction } => write!(
+                f, "Did not recognize opcode {opcode:#b} in instruction {instruction:#010x}"),
+            RegexError::UnsupportedVersion { version } => write!(
+                f, "Unsupported bytecode version {version}"),
+            RegexError::ChecksumMismatch { expected, actual } => write!(
+                f, "Checksum {actual:#010x} doesn't match the {expected:#010x} in the header"),
+            RegexError::EmptyProgram => write!(f, "Program has no instructions"),
+            RegexError::InvalidDestination { pc, dest } => write!(
+                f, "Instruction {pc} has out of range destination {dest}"),
+            RegexError::DestinationOverflow { pc, dest } => write!(
+                f, "Instruction {pc} has destination {dest}, which is too big to encode"),
+            RegexError::UnsupportedInstruction { pc } => write!(
+                f, "Instruction {pc} can't be encoded in this version of the bytecode"),
+            RegexError::SaveIndexOverflow { pc, index } => write!(
+                f, "Instruction {pc} has save index {index}, which is too big to encode"),
+            RegexError::InvalidPattern { pos, message } => write!(
+                f, "Invalid pattern at position {pos}: {message}"),
+            RegexError::ThreadLimitExceeded { limit } => write!(
+                f, "Search needed more than {limit} threads"),
+            RegexError::Timeout => write!(f, "Search timed out"),
+            RegexError::ComplexityLimit { steps_per_char } => write!(
+                f, "Search ran more than {steps_per_char} steps per character"),
+            RegexError::EpsilonCycle { pc } => write!(
+                f, "Instruction {pc} is part of a loop that doesn't consume any input"),
+            RegexError::InvalidAssembly { line, message } => write!(
+                f, "Invalid assembly on line {line}: {message}"),
+            RegexError::Lint(lint) => write!(f, "{lint}"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for RegexError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RegexError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<io::Error> for RegexError {
+    fn from(err: io::Error) -> Self {
+        RegexError::Io(err)
+    }
+}
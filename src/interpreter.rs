@@ -1,27 +1,268 @@
 mod thread;
-use crate::regex::Instruction;
-use crate::interpreter::thread::{ThreadList, ThreadGroup};
-use std::mem;
+use crate::error::RegexError;
+use crate::regex::{bitmap_contains, Assertion, Instruction, PatternSet};
+use crate::regex::analyze;
+use crate::interpreter::thread::{Merge, ThreadList, ThreadGroup, VisitedSet};
+use alloc::borrow::Cow;
+use core::fmt;
+use core::mem;
+use core::str;
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+/// How many instructions to run between checks of the clock when a search has a timeout.
+#[cfg(feature = "std")]
+const TIMEOUT_CHECK_STEPS: u64 = 4096;
+
+/// Counters describing how much work a search did.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct SearchStats {
+    /// The number of instructions executed across all threads.
+    pub steps: u64,
+    /// The largest number of thread groups that were active for a single input character.
+    pub max_threads: usize,
+    /// The number of input characters that were fed to the program.
+    pub chars_scanned: usize,
+}
+
+impl SearchStats {
+    /// Folds the stats of another search into this one.
+    pub fn merge(&mut self, other: &SearchStats) {
+        self.steps += other.steps;
+        self.max_threads = self.max_threads.max(other.max_threads);
+        self.chars_scanned += other.chars_scanned;
+    }
+}
+
+/// One instruction run by a group of threads, as recorded by `search_traced`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TraceEvent {
+    /// The position in the input, which is the index of the byte being looked at.
+    pub index: usize,
+    pub pc: usize,
+    pub instruction: Instruction,
+    /// How many threads ran the instruction together.
+    pub threads: usize,
+    pub outcome: Outcome,
+}
+
+/// What happened to the threads that ran an instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// Moved on to the given pc without consuming the byte.
+    Continued(usize),
+    /// Consumed the byte and moved on to the next instruction.
+    Consumed,
+    /// Didn't get past the instruction, so the threads died.
+    Rejected,
+    /// Split into copies at both pcs.
+    Split(usize, usize),
+    /// Reached a match.
+    Matched,
+}
+
+impl fmt::Display for TraceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "@{} {}: {} x{} -> ", self.index, self.pc, self.instruction, self.threads)?;
+        match self.outcome {
+            Outcome::Continued(pc) => write!(f, "continue to {pc}"),
+            Outcome::Consumed => write!(f, "consume"),
+            Outcome::Rejected => write!(f, "reject"),
+            Outcome::Split(pc1, pc2) => write!(f, "split to {pc1}, {pc2}"),
+            Outcome::Matched => write!(f, "match"),
+        }
+    }
+}
+
+/// Settings that change how a search is run.
+///
+/// The defaults search the same way as `search` does, so new settings can be added here without
+/// changing the behavior of existing callers.
+#[derive(Clone, Default, Debug)]
+pub struct SearchOptions {
+    /// Only allow matches that start where the search does, even if the program could start one
+    /// anywhere.
+    pub anchored: bool,
+    /// Compare ASCII letters without regard to case. Other bytes still have to match exactly.
+    pub case_insensitive: bool,
+    /// Which characters `case_insensitive` folds. This only has an effect on `regex::compile_with`.
+    pub fold: Fold,
+    /// Let `^` and `$` match at the start and end of every line in the input, not just at the start
+    /// and end of the whole thing, by treating `Start` and `End` assertions like `LineStart` and
+    /// `LineEnd`.
+    pub multiline: bool,
+    /// The most threads that can be alive at once before the search gives up, or `None` for no
+    /// limit.
+    pub max_threads: Option<usize>,
+    /// How long the search can run before giving up, or `None` to let it take as long as it needs.
+    /// Without `std` there's no clock to check, so this isn't available.
+    #[cfg(feature = "std")]
+    pub timeout: Option<Duration>,
+    /// Which characters count as part of a word for `\b` and `\B`.
+    pub word_class: WordClass,
+    /// How many instructions the threads can run between them for each byte of input before the
+    /// search gives up, or `None` for no limit. Unlike `timeout`, this always gives up at the same
+    /// point for the same input.
+    pub max_steps_per_char: Option<u64>,
+}
+
+/// The characters that case insensitive searches fold.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub enum Fold {
+    /// Only the ASCII letters, which the interpreter can fold one byte at a time.
+    #[default]
+    Ascii,
+    /// Every character with a simple case mapping. The interpreter can't fold characters that are
+    /// more than one byte long, so this needs the program to be compiled with `compile_with`.
+    Unicode,
+}
+
+/// The characters that word boundary assertions count as word characters.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub enum WordClass {
+    /// The bytes matched by `\w`: ASCII letters, digits and `_`. Only the bytes on either side of
+    /// the position need to be looked at, so this is cheap, but it treats every byte of a non-ASCII
+    /// character as a non-word character.
+    #[default]
+    Ascii,
+    /// Every alphanumeric Unicode character, along with `_`. The characters on either side of the
+    /// position have to be decoded from UTF-8 each time a boundary is checked, which can mean looking
+    /// at up to 4 bytes in each direction. Bytes that aren't part of a valid character count as
+    /// non-word characters.
+    Unicode,
+}
+
+/// A match found by the executor, along with the pc of the instruction that reported it.
+type TaggedMatch = (usize, (usize, usize));
+
+/// The lists that each step of a search fills in and empties again. They only need to live for one
+/// step, but they're kept together so that they can be reused for every character of a long input,
+/// and by `Searcher` for every search.
+struct StepBuffers {
+    /// The threads that are still running for the current input character.
+    temp_threads: ThreadList,
+    /// The threads waiting for the next input character.
+    next_threads: ThreadList,
+    /// The threads that have already run for the current input character.
+    visited: VisitedSet,
+}
+
+impl StepBuffers {
+    fn new(len: usize) -> Self {
+        StepBuffers {
+            temp_threads: ThreadList::new(len),
+            next_threads: ThreadList::new(len),
+            visited: VisitedSet::new(len),
+        }
+    }
+}
 
 struct Executor<'a> {
     program: &'a[Instruction],
+    input: &'a [u8],
+    options: &'a SearchOptions,
+    /// Threads that matched a backreference and are waiting for the input to reach the position
+    /// where they can continue.
+    delayed_threads: Vec<(usize, usize, ThreadGroup)>,
+    /// Stop as soon as any match is found, for when the caller doesn't care which one it is.
+    first_match_only: bool,
+    /// Where to record each instruction that runs, if anywhere. This is `None` unless the search
+    /// was asked for a trace.
+    trace: Option<Vec<TraceEvent>>,
+    /// How many thread groups were active at each position in the input, if the search was asked
+    /// for a profile.
+    thread_profile: Option<Vec<usize>>,
+    /// Whether saves actually record anything. When nothing needs the captures, leaving them out
+    /// means that every thread at the same instruction is identical, so they all merge into one.
+    track_captures: bool,
+    /// Whether to keep only the earliest (or latest) starting thread at each instruction.
+    ///
+    /// A compiled program starts a new thread at every offset, and each one saves a different start,
+    /// so without this there can be a thread for every offset seen so far and a search is quadratic
+    /// in the length of the input. Threads at the same instruction all end the same way, so when
+    /// only the leftmost match is wanted the earliest one is the only one that matters, and when
+    /// only the rightmost one is wanted the latest one is. That isn't true with backreferences,
+    /// which look at the other groups, or for searches that want the matches at every start.
+    merge_threads: Merge,
+    buffers: StepBuffers,
+    /// How many times any single thread has run an instruction. The step count in `stats` counts
+    /// each group once, which misses the blowup when captures keep the threads from merging.
+    thread_steps: u64,
+    stats: SearchStats,
 }
 
 impl <'a> Executor<'a> {
-    fn new(prog: &'a[Instruction]) -> Self {
+    fn new(prog: &'a[Instruction], input: &'a [u8], options: &'a SearchOptions) -> Self {
+        Executor::with_buffers(prog, input, options, StepBuffers::new(prog.len()))
+    }
+
+    /// Like `new`, but reuses buffers from an earlier search with the same program.
+    fn with_buffers(
+            prog: &'a[Instruction],
+            input: &'a [u8],
+            options: &'a SearchOptions,
+            buffers: StepBuffers
+        ) -> Self {
         Executor {
             program: prog,
+            input,
+            options,
+            delayed_threads: Vec::new(),
+            first_match_only: false,
+            trace: None,
+            thread_profile: None,
+            track_captures: true,
+            merge_threads: if prog.iter().any(|inst| matches!(inst, Instruction::Backref(_))) {
+                Merge::None
+            } else {
+                Merge::Earliest
+            },
+            buffers,
+            thread_steps: 0,
+            stats: SearchStats::default(),
+        }
+    }
+
+    /// Checks an assertion at the position just before the byte at `char_index`.
+    fn assertion_holds(&self, assertion: Assertion, char_index: usize) -> bool {
+        let previous = char_index.checked_sub(1).map(|index| self.input[index]);
+        let next = self.input.get(char_index).copied();
+        match assertion {
+            Assertion::Start if !self.options.multiline => previous.is_none(),
+            Assertion::End if !self.options.multiline => next.is_none(),
+            Assertion::Start | Assertion::LineStart => matches!(previous, None | Some(b'\n')),
+            Assertion::End | Assertion::LineEnd => matches!(next, None | Some(b'\n')),
+            Assertion::WordBoundary => self.is_word_boundary(char_index),
+            Assertion::NotWordBoundary => !self.is_word_boundary(char_index),
+        }
+    }
+
+    /// Checks whether there's a word character on exactly one side of the position just before the
+    /// byte at `char_index`.
+    fn is_word_boundary(&self, char_index: usize) -> bool {
+        match self.options.word_class {
+            WordClass::Ascii => {
+                let is_word = |c: Option<&u8>| c.is_some_and(|c| c.is_ascii_alphanumeric() || *c == b'_');
+                let previous = char_index.checked_sub(1).and_then(|index| self.input.get(index));
+                is_word(previous) != is_word(self.input.get(char_index))
+            }
+            WordClass::Unicode => {
+                let is_word = |c: Option<char>| c.is_some_and(|c| c.is_alphanumeric() || c == '_');
+                is_word(char_before(self.input, char_index)) != is_word(char_after(self.input, char_index))
+            }
         }
     }
 
     fn _execution_step(
-            &self,
+            &mut self,
             current_threads: &mut ThreadList,
             temp_threads: &mut ThreadList,
             next_threads: &mut ThreadList,
+            visited: &mut VisitedSet,
             char_index: usize,
-            input_char: u8
-        ) -> Vec<(usize, usize)> {
+            input_char: Option<u8>
+        ) -> Vec<TaggedMatch> {
         let mut consume_and_step = |pc: usize, thread_group: ThreadGroup| {
             next_threads.add_thread(pc, thread_group);
         };
@@ -31,89 +272,999 @@ impl <'a> Executor<'a> {
         let mut matches = Vec::new();
         for mut thread_group in current_threads.iter_mut() {
             let pc = thread_group.pc;
-            match self.program[thread_group.pc] {
+            // A jump off the end of the program (from a hand-written or unvalidated program) just
+            // kills the thread rather than taking down the whole search.
+            let Some(&instruction) = self.program.get(pc) else {
+                continue;
+            };
+            visited.filter(&mut thread_group, self.merge_threads);
+            if thread_group.is_empty() {
+                continue;
+            }
+            self.stats.steps += 1;
+            let threads = thread_group.len();
+            self.thread_steps += threads as u64;
+            let outcome = match instruction {
                 Instruction::Save(dest, is_match) => {
-                    thread_group.save(dest, char_index);
+                    if self.track_captures {
+                        thread_group.save(dest, char_index);
+                    }
                     if is_match {
-                        let mut tmp_matches = thread_group.get_match_data(0);
-                        matches.append(&mut tmp_matches)
+                        let tmp_matches = thread_group.get_match_data(0);
+                        matches.extend(tmp_matches.into_iter().map(|span| (pc, span)));
+                        Outcome::Matched
                     } else {
                         step_execution(pc + 1, thread_group);
+                        Outcome::Continued(pc + 1)
                     }
                 }
                 Instruction::Branch{c_min, c_max, dest, consume, inverted} => {
-                    let in_range = c_min <= input_char && input_char <= c_max;
+                    let in_range = |c: u8| c_min <= c && c <= c_max;
+                    // There's no byte at the end of the input, so no range contains it. A jump is
+                    // still taken though, since it doesn't look at the input at all.
+                    let in_range = match input_char {
+                        Some(c) => in_range(c) || (self.options.case_insensitive && in_range(other_case(c))),
+                        None => !consume && c_min == 0 && c_max == u8::MAX,
+                    };
                     let is_match = in_range != inverted;
                     match (consume, is_match) {
-                        (true, true) => consume_and_step(pc+1, thread_group),
-                        (true, false) => (),
-                        (false, true) => step_execution(dest, thread_group),
-                        (false, false) => step_execution(pc+1, thread_group),
-                    };
+                        (true, true) if input_char.is_some() => {
+                            consume_and_step(pc+1, thread_group);
+                            Outcome::Consumed
+                        }
+                        (true, _) => Outcome::Rejected,
+                        (false, true) => {
+                            step_execution(dest, thread_group);
+                            Outcome::Continued(dest)
+                        }
+                        (false, false) => {
+                            step_execution(pc+1, thread_group);
+                            Outcome::Continued(pc + 1)
+                        }
+                    }
                 }
+                Instruction::Byte(c) => {
+                    if input_char == Some(c)
+                        || (self.options.case_insensitive && input_char == Some(other_case(c))) {
+                        consume_and_step(pc + 1, thread_group);
+                        Outcome::Consumed
+                    } else {
+                        Outcome::Rejected
+                    }
+                }
+                Instruction::ClassBitmap(bitmap) => {
+                    let contains = |c: u8| bitmap_contains(&bitmap, c)
+                        || (self.options.case_insensitive && bitmap_contains(&bitmap, other_case(c)));
+                    if input_char.is_some_and(contains) {
+                        consume_and_step(pc + 1, thread_group);
+                        Outcome::Consumed
+                    } else {
+                        Outcome::Rejected
+                    }
+                }
+                Instruction::Assert(assertion) => {
+                    if self.assertion_holds(assertion, char_index) {
+                        step_execution(pc + 1, thread_group);
+                        Outcome::Continued(pc + 1)
+                    } else {
+                        Outcome::Rejected
+                    }
+                }
+                Instruction::Fail => Outcome::Rejected,
                 Instruction::Split(pc1, pc2) => {
                     step_execution(pc1, thread_group.clone());
                     step_execution(pc2, thread_group);
+                    Outcome::Split(pc1, pc2)
                 }
+                Instruction::Backref(group) => {
+                    let mut outcome = Outcome::Rejected;
+                    // Every thread may have captured something different, so check them one by one.
+                    for thread in thread_group.split_threads() {
+                        let (start, end) = thread.get_match_data(group)[0];
+                        let captured = &self.input[start..end.max(start)];
+                        let end_index = char_index + captured.len();
+                        let is_match = match self.input.get(char_index..end_index) {
+                            Some(text) if self.options.case_insensitive => text.eq_ignore_ascii_case(captured),
+                            text => text == Some(captured),
+                        };
+                        if !is_match {
+                            continue;
+                        }
+
+                        outcome = Outcome::Consumed;
+                        match captured.len() {
+                            0 => step_execution(pc + 1, thread),
+                            1 => consume_and_step(pc + 1, thread),
+                            _ => self.delayed_threads.push((end_index, pc + 1, thread)),
+                        }
+                    }
+                    outcome
+                }
+            };
+
+            if let Some(trace) = &mut self.trace {
+                trace.push(TraceEvent { index: char_index, pc, instruction, threads, outcome });
             }
         }
         matches
     }
 
-    fn execution_step(&mut self, current_threads: &mut ThreadList, char_index: usize, input_char: u8) -> Vec<(usize, usize)> {
-        let mut temp_threads = ThreadList::new(self.program.len());
-        let mut next_threads = ThreadList::new(self.program.len());
+    fn execution_step(
+            &mut self,
+            current_threads: &mut ThreadList,
+            char_index: usize,
+            input_char: Option<u8>
+        ) -> Result<Vec<TaggedMatch>, RegexError> {
+        // Borrow the buffers from the executor so that they can be passed alongside it.
+        let mut temp_threads = mem::take(&mut self.buffers.temp_threads);
+        let mut next_threads = mem::take(&mut self.buffers.next_threads);
+        let mut visited = mem::take(&mut self.buffers.visited);
+        visited.clear();
         let mut matches = Vec::new();
 
+        for (_, pc, thread_group) in self.delayed_threads.extract_if(.., |(index, _, _)| *index == char_index) {
+            current_threads.add_thread(pc, thread_group);
+        }
+
         while !current_threads.is_empty() {
-            matches.append(&mut self._execution_step(current_threads, &mut temp_threads, &mut next_threads, char_index, input_char));
+            matches.append(&mut self._execution_step(current_threads, &mut temp_threads, &mut next_threads, &mut visited, char_index, input_char));
             current_threads.clear();
             mem::swap(current_threads, &mut temp_threads);
+
+            if let Some(limit) = self.options.max_threads {
+                let live_threads = current_threads.thread_count()
+                    + next_threads.thread_count()
+                    + self.delayed_threads.len();
+                if live_threads > limit {
+                    // Put the buffers back so that a `Searcher` can keep using them.
+                    self.buffers = StepBuffers { temp_threads, next_threads, visited };
+                    return Err(RegexError::ThreadLimitExceeded { limit });
+                }
+            }
+        }
+
+        // Each pc only counts once, however many times threads got to it.
+        let active_threads = visited.len();
+        self.stats.max_threads = self.stats.max_threads.max(active_threads);
+        if let Some(profile) = &mut self.thread_profile {
+            // Searches that start partway through the input didn't have any threads before then.
+            profile.resize(char_index, 0);
+            profile.push(active_threads);
         }
 
-        // Swap the next threads into current.
+        // Swap the next threads into current, and keep the old current list to fill in next time.
         current_threads.clear();
         mem::swap( current_threads, &mut next_threads);
+        self.buffers = StepBuffers { temp_threads, next_threads, visited };
 
-        matches
+        Ok(matches)
     }
 
-    fn run(&mut self, current_threads: &mut ThreadList, input: &'a str) -> Option<(usize, usize)> {
+    fn run(&mut self, current_threads: &mut ThreadList, start: usize) -> Result<Vec<TaggedMatch>, RegexError> {
         let mut all_matches = Vec::new();
+        let input = self.input;
+        #[cfg(feature = "std")]
+        let deadline = self.options.timeout.map(|timeout| Instant::now() + timeout);
+        #[cfg(feature = "std")]
+        let mut next_check = TIMEOUT_CHECK_STEPS;
+        // Count the final step at the end of the input as a character too.
+        let max_steps = self.options.max_steps_per_char
+            .map(|limit| limit.saturating_mul((input.len().saturating_sub(start) + 1) as u64));
 
-        for (char_index, input_char) in input.chars().enumerate() {
-            let char_u8 = if input_char.is_ascii() {
-                let mut char_buf: [u8; 1] = [0; 1];
-                input_char.encode_utf8(& mut char_buf);
-                char_buf[0]
-            } else {
-                // If it's unicode, send an invalid byte (that's not 0xFF)
-                0xFE
-            };
+        // A hand-written program might have some other way of skipping ahead than the search
+        // prefix, so anchored searches still have to check where the matches start.
+        let anchored = self.options.anchored;
+        let keep = |(_, span): &TaggedMatch| !anchored || span.0 == start;
 
-            all_matches.append(&mut self.execution_step(current_threads, char_index, char_u8));
+        for (char_index, input_char) in input.iter().enumerate().skip(start) {
+            let step_matches = self.execution_step(current_threads, char_index, Some(*input_char))?;
+            all_matches.extend(step_matches.into_iter().filter(keep));
+            self.stats.chars_scanned += 1;
+            if let Some(max_steps) = max_steps && self.thread_steps > max_steps {
+                return Err(RegexError::ComplexityLimit {
+                    steps_per_char: self.options.max_steps_per_char.unwrap_or_default()});
+            }
+            if self.first_match_only && !all_matches.is_empty() {
+                return Ok(all_matches);
+            }
+
+            // Reading the clock isn't free, so only do it every few thousand steps.
+            #[cfg(feature = "std")]
+            if let Some(deadline) = deadline && self.stats.steps >= next_check {
+                if Instant::now() > deadline {
+                    return Err(RegexError::Timeout);
+                }
+                next_check = self.stats.steps + TIMEOUT_CHECK_STEPS;
+            }
         }
 
         // Run one final execution step in case there are any threads on a `match`
-        all_matches.append(&mut self.execution_step(current_threads, input.len(), 0));
+        let step_matches = self.execution_step(current_threads, input.len(), None)?;
+        all_matches.extend(step_matches.into_iter().filter(keep));
 
-        let longer_match = |wrapped_match1: Option<(usize, usize)>, match2: &(usize, usize)| -> Option<(usize, usize)> {
-            if let Some(match1) = wrapped_match1 {
-                if match1.1 - match1.0 > match2.1 - match2.0 {
-                    return wrapped_match1;
-                }
+        Ok(all_matches)
+    }
+}
+
+/// Decodes the character that ends just before `index`, if the bytes there are valid UTF-8.
+fn char_before(input: &[u8], index: usize) -> Option<char> {
+    (1..=index.min(4))
+        .find_map(|len| str::from_utf8(&input[index - len..index]).ok())
+        .and_then(|text| text.chars().next())
+}
+
+/// Decodes the character that starts at `index`, if the bytes there are valid UTF-8.
+fn char_after(input: &[u8], index: usize) -> Option<char> {
+    (1..=input.len().saturating_sub(index).min(4))
+        .find_map(|len| str::from_utf8(&input[index..index + len]).ok())
+        .and_then(|text| text.chars().next())
+}
+
+/// Swaps the case of an ASCII letter, leaving every other byte alone.
+fn other_case(c: u8) -> u8 {
+    if c.is_ascii_alphabetic() { c ^ 0x20 } else { c }
+}
+
+/// Prefers the match that starts first, falling back to the longer one when they start together.
+fn leftmost_longer_match(wrapped_match1: Option<(usize, usize)>, match2: &(usize, usize)) -> Option<(usize, usize)> {
+    if let Some(match1) = wrapped_match1
+        && (match1.0 < match2.0 || (match1.0 == match2.0 && match1.1 >= match2.1)) {
+        return wrapped_match1;
+    }
+    Some(*match2)
+}
+
+/// Prefers the match that starts last, falling back to the longer one when they start together.
+fn rightmost_match(wrapped_match1: Option<(usize, usize)>, match2: &(usize, usize)) -> Option<(usize, usize)> {
+    if let Some(match1) = wrapped_match1
+        && (match1.0 > match2.0 || (match1.0 == match2.0 && match1.1 >= match2.1)) {
+        return wrapped_match1;
+    }
+    Some(*match2)
+}
+
+/// The number of capture slots that threads need to run the program.
+fn capture_slots(prog: &[Instruction]) -> usize {
+    prog.iter()
+        .map(|inst| match *inst {
+            Instruction::Save(index, _) => index + 1,
+            Instruction::Backref(group) => group * 2 + 2,
+            _ => 0,
+        })
+        .fold(2, usize::max)
+}
+
+/// Makes sure that the program records where its matches start.
+///
+/// Every thread starts out with slot 0 set to where the search started, which is right for an
+/// anchored program. A program that starts with the unanchored search prefix has to save slot 0
+/// itself after the prefix, like `compile` does, or every match would look like it starts at the
+/// beginning of the input. When a hand-written program leaves that out, this adds the missing
+/// `Save(0)` and moves the destinations after it along to match. The match itself still needs to be
+/// a `Save(1, true)` so that the end is recorded.
+fn with_match_start(prog: &[Instruction]) -> Cow<'_, [Instruction]> {
+    let body_start = analyze::skip_search_prefix(prog);
+    if body_start == 0 || prog.iter().any(|inst| matches!(inst, Instruction::Save(0, _))) {
+        return Cow::Borrowed(prog);
+    }
+
+    // The prefix has to go through the new save, but jumps from inside the body skip over it.
+    let shift = |dest| if dest >= body_start { dest + 1 } else { dest };
+    let mut patched = prog[..body_start].to_vec();
+    patched.push(Instruction::Save(0, false));
+    patched.extend(prog[body_start..].iter().map(|inst| inst.map_destinations(shift)));
+    Cow::Owned(patched)
+}
+
+/// Adds the thread that a search starting at `start` begins with.
+fn start_thread(prog: &[Instruction], start: usize, options: &SearchOptions, threads: &mut ThreadList) {
+    // An anchored search has no use for the threads that the search prefix would start later on.
+    let start_pc = if options.anchored { analyze::skip_search_prefix(prog) } else { 0 };
+    let mut thread_group = ThreadGroup::new(start_pc, capture_slots(prog));
+    thread_group.save(0, start);
+    threads.add_thread(start_pc, thread_group);
+}
+
+/// The ways that the searches sharing `run_executor` differ from each other.
+#[derive(Default)]
+struct Run<'r> {
+    /// Stop as soon as any match is found.
+    first_match_only: bool,
+    /// Find the matches from every start instead of just the leftmost ones, which keeps threads at
+    /// the same instruction from being merged.
+    all_starts: bool,
+    /// Look for the rightmost match, which merges threads at the same instruction onto the latest
+    /// start instead of the earliest.
+    rightmost: bool,
+    trace: Option<&'r mut Vec<TraceEvent>>,
+    profile: Option<&'r mut Vec<usize>>,
+}
+
+fn run_from(
+        prog: &[Instruction],
+        input: &[u8],
+        start: usize,
+        options: &SearchOptions
+    ) -> Result<(Vec<TaggedMatch>, SearchStats), RegexError> {
+    run_executor(prog, input, start, options, Run::default())
+}
+
+fn run_executor(
+        prog: &[Instruction],
+        input: &[u8],
+        start: usize,
+        options: &SearchOptions,
+        run: Run
+    ) -> Result<(Vec<TaggedMatch>, SearchStats), RegexError> {
+    let Run { first_match_only, all_starts, rightmost, trace, profile } = run;
+    let prog = &*with_match_start(prog);
+    let mut executor = Executor::new(prog, input, options);
+    executor.first_match_only = first_match_only;
+    executor.merge_threads = match executor.merge_threads {
+        _ if all_starts => Merge::None,
+        Merge::Earliest if rightmost => Merge::Latest,
+        merge => merge,
+    };
+    executor.trace = trace.as_ref().map(|_| Vec::new());
+    executor.thread_profile = profile.as_ref().map(|_| Vec::new());
+    // Backreferences need their groups, and anchoring needs the start of each match to check it.
+    executor.track_captures = !first_match_only
+        || options.anchored
+        || prog.iter().any(|inst| matches!(inst, Instruction::Backref(_)));
+    let mut current_threads = ThreadList::new(prog.len());
+    start_thread(prog, start, options, &mut current_threads);
+    let all_matches = executor.run(&mut current_threads, start);
+    if let Some(trace) = trace {
+        // Hand over what was recorded even if the search failed, since that's when it's most useful.
+        *trace = executor.trace.take().unwrap_or_default();
+    }
+    if let Some(profile) = profile {
+        *profile = executor.thread_profile.take().unwrap_or_default();
+    }
+    Ok((all_matches?, executor.stats))
+}
+
+/// Finds the leftmost-longest match in `input`, like POSIX does: the match that starts first, and
+/// the longest of the matches that start there.
+pub fn search(prog: &[Instruction], input: &str) -> Option<(usize, usize)> {
+    // The default options don't set any limits, so the search can't fail.
+    search_with(prog, input, &SearchOptions::default()).unwrap_or_default()
+}
+
+/// Like `search`, but with settings to control how the search runs.
+pub fn search_with(
+        prog: &[Instruction],
+        input: &str,
+        options: &SearchOptions
+    ) -> Result<Option<(usize, usize)>, RegexError> {
+    Ok(search_bytes_with(prog, input.as_bytes(), options)?.0)
+}
+
+/// Like `search`, but also reports how much work the engine had to do to find the match.
+pub fn search_with_stats(prog: &[Instruction], input: &str) -> (Option<(usize, usize)>, SearchStats) {
+    search_bytes_with_stats(prog, input.as_bytes())
+}
+
+/// Searches input that isn't necessarily UTF-8, since the engine only ever looks at bytes.
+pub fn search_bytes(prog: &[Instruction], input: &[u8]) -> Option<(usize, usize)> {
+    search_bytes_with_stats(prog, input).0
+}
+
+/// Like `search_bytes`, but also reports how much work the engine had to do to find the match.
+pub fn search_bytes_with_stats(prog: &[Instruction], input: &[u8]) -> (Option<(usize, usize)>, SearchStats) {
+    search_bytes_with(prog, input, &SearchOptions::default()).unwrap_or_default()
+}
+
+/// Like `search_with`, but for input that isn't necessarily UTF-8, and also reports how much work
+/// the engine had to do.
+pub fn search_bytes_with(
+        prog: &[Instruction],
+        input: &[u8],
+        options: &SearchOptions
+    ) -> Result<(Option<(usize, usize)>, SearchStats), RegexError> {
+    let (all_matches, stats) = run_from(prog, input, 0, options)?;
+    Ok((all_matches.iter().map(|(_, span)| span).fold(None, leftmost_longer_match), stats))
+}
+
+/// Checks whether the program matches anywhere in `input`.
+///
+/// This gives the same answer as `search(..).is_some()`, but it stops at the first match it comes
+/// across instead of running to the end of the input to find the longest one. Since nothing needs
+/// to know where the match is, the threads don't have to keep track of their captures either, which
+/// lets the threads for every possible start merge together.
+pub fn is_match(prog: &[Instruction], input: &str) -> bool {
+    is_match_bytes_with(prog, input.as_bytes(), &SearchOptions::default()).unwrap_or_default()
+}
+
+/// Like `is_match`, but for input that isn't necessarily UTF-8 and with settings to control how the
+/// search runs.
+pub fn is_match_bytes_with(
+        prog: &[Instruction],
+        input: &[u8],
+        options: &SearchOptions
+    ) -> Result<bool, RegexError> {
+    let (all_matches, _) = run_executor(prog, input, 0, options, Run { first_match_only: true, ..Run::default() })?;
+    Ok(!all_matches.is_empty())
+}
+
+/// Like `search`, but also records every instruction that ran along the way, for working out why a
+/// program did or didn't match.
+///
+/// The pcs in the trace are for the program as it actually ran, which is only different from `prog`
+/// when `search` had to add a `Save(0)` to it.
+pub fn search_traced(prog: &[Instruction], input: &str) -> (Option<(usize, usize)>, Vec<TraceEvent>) {
+    let mut trace = Vec::new();
+    let result = search_bytes_traced(prog, input.as_bytes(), &SearchOptions::default(), &mut trace);
+    (result.ok().flatten(), trace)
+}
+
+/// Like `search_traced`, but for input that isn't necessarily UTF-8 and with settings to control how
+/// the search runs. The trace is filled in even if the search fails.
+pub fn search_bytes_traced(
+        prog: &[Instruction],
+        input: &[u8],
+        options: &SearchOptions,
+        trace: &mut Vec<TraceEvent>
+    ) -> Result<Option<(usize, usize)>, RegexError> {
+    let (all_matches, _) = run_executor(prog, input, 0, options, Run { trace: Some(trace), ..Run::default() })?;
+    Ok(all_matches.iter().map(|(_, span)| span).fold(None, leftmost_longer_match))
+}
+
+/// Like `search`, but also counts how many thread groups were active at each position in the input,
+/// for finding the part of a pattern that blows up. Index `i` of the profile is the position just
+/// before byte `i`, and the last entry is the end of the input.
+pub fn search_profiled(prog: &[Instruction], input: &str) -> (Option<(usize, usize)>, Vec<usize>) {
+    let mut profile = Vec::new();
+    let result = search_bytes_profiled(prog, input.as_bytes(), &SearchOptions::default(), &mut profile);
+    (result.ok().flatten(), profile)
+}
+
+/// Like `search_profiled`, but for input that isn't necessarily UTF-8 and with settings to control
+/// how the search runs. The profile is filled in as far as the search got even if it fails.
+pub fn search_bytes_profiled(
+        prog: &[Instruction],
+        input: &[u8],
+        options: &SearchOptions,
+        profile: &mut Vec<usize>
+    ) -> Result<Option<(usize, usize)>, RegexError> {
+    let (all_matches, _) = run_executor(prog, input, 0, options, Run { profile: Some(profile), ..Run::default() })?;
+    Ok(all_matches.iter().map(|(_, span)| span).fold(None, leftmost_longer_match))
+}
+
+/// Searches for several programs at once, returning the index of the pattern that matched along
+/// with where it matched.
+///
+/// As with `search`, the leftmost-longest match wins, and ties go to the pattern that comes first.
+pub fn search_multi(progs: &[Vec<Instruction>], input: &str) -> Option<(usize, (usize, usize))> {
+    search_set(&PatternSet::new(progs), input)
+}
+
+/// Like `search_multi`, but with the programs already combined so they can be reused.
+pub fn search_set(set: &PatternSet, input: &str) -> Option<(usize, (usize, usize))> {
+    let (all_matches, _) = run_from(set.program(), input.as_bytes(), 0, &SearchOptions::default()).ok()?;
+
+    let mut best: Option<(usize, (usize, usize))> = None;
+    for (pc, span) in &all_matches {
+        let pattern = set.pattern_at(*pc);
+        let is_better = match best {
+            None => true,
+            Some((best_pattern, best_span)) => {
+                // Earlier starts first, then longer ends, then earlier patterns.
+                (span.0, best_span.1, pattern) < (best_span.0, span.1, best_pattern)
             }
-            Some(*match2)
         };
+        if is_better {
+            best = Some((pattern, *span));
+        }
+    }
+    best
+}
+
+/// Finds the match that starts furthest to the right in `input`, rather than the leftmost one.
+///
+/// This costs about the same as `search`. It merges the threads at each instruction the same way,
+/// except that it keeps the one that started last, since a later start at the same instruction
+/// always leads to a match that's further right.
+pub fn search_last(prog: &[Instruction], input: &str) -> Option<(usize, usize)> {
+    let run = Run { rightmost: true, ..Run::default() };
+    let (all_matches, _) = run_executor(prog, input.as_bytes(), 0, &SearchOptions::default(), run).ok()?;
+    all_matches.iter().map(|(_, span)| span).fold(None, rightmost_match)
+}
 
-        all_matches.iter().fold(None, longer_match)
+/// Finds the leftmost-longest match that starts at or after the byte offset `start`, so that
+/// successive calls can walk through the input in order.
+pub fn search_at(prog: &[Instruction], input: &str, start: usize) -> Option<(usize, usize)> {
+    search_bytes_at(prog, input.as_bytes(), start, &SearchOptions::default())
+}
+
+fn search_bytes_at(
+        prog: &[Instruction],
+        input: &[u8],
+        start: usize,
+        options: &SearchOptions
+    ) -> Option<(usize, usize)> {
+    let (all_matches, _) = run_from(prog, input, start, options).ok()?;
+    all_matches.iter().map(|(_, span)| span).fold(None, leftmost_longer_match)
+}
+
+/// Finds all of the non-overlapping matches in `input`, from left to right.
+pub fn search_all(prog: &[Instruction], input: &str) -> Vec<(usize, usize)> {
+    Matches::new(prog, input).collect()
+}
+
+/// Like `search_all`, but for input that isn't necessarily UTF-8.
+pub fn search_all_bytes(prog: &[Instruction], input: &[u8]) -> Vec<(usize, usize)> {
+    Matches::from_bytes(prog, input).collect()
+}
+
+/// Finds all of the non-overlapping matches in `text` one line at a time, yielding the 1-based
+/// number of the line each one is on along with where it starts and ends in that line.
+///
+/// Lines are split the same way as `str::lines`, so a match can never run across a line break and
+/// a `\r` before the `\n` isn't part of the line.
+pub fn matches_in(prog: &[Instruction], text: &str) -> impl Iterator<Item = (usize, usize, usize)> {
+    text.lines().enumerate().flat_map(move |(index, line)| {
+        Matches::new(prog, line).map(move |(start, end)| (index + 1, start, end))
+    })
+}
+
+/// Finds the longest match starting at each offset in `input`, even where the matches overlap.
+///
+/// This doesn't rerun the search from every offset. The threads for every possible start run side
+/// by side in a single pass, and it can only find matches that start where the program itself can
+/// start one, which is everywhere for compiled patterns.
+///
+/// That pass is O(n²) in the length of the input, unlike `search`. Every start needs its own
+/// threads to find its own match, so threads at the same instruction are never merged, and a
+/// pattern like `x+y` on a long run of `x`s keeps a thread alive for every offset seen so far. On a
+/// 4KB line of `x`s that's around a thousand times slower than `search` (see `benches/long_line.rs`).
+/// Avoid it on long lines, or when only the first or last match is wanted, where `search` and
+/// `search_last` stay linear.
+pub fn search_overlapping(prog: &[Instruction], input: &str) -> Vec<(usize, usize)> {
+    let run = Run { all_starts: true, ..Run::default() };
+    let Ok((all_matches, _)) = run_executor(prog, input.as_bytes(), 0, &SearchOptions::default(), run) else {
+        return Vec::new();
+    };
+
+    // Put the longest match for each start first, then drop the rest.
+    let mut spans: Vec<(usize, usize)> = all_matches.into_iter().map(|(_, span)| span).collect();
+    spans.sort_by(|span1, span2| span1.0.cmp(&span2.0).then(span2.1.cmp(&span1.1)));
+    spans.dedup_by_key(|span| span.0);
+    spans
+}
+
+/// Runs one program over many inputs, keeping the thread lists around between searches instead of
+/// allocating new ones each time.
+///
+/// Every search starts from scratch, so the results are always the same as calling `search` on
+/// each input.
+pub struct Searcher<'p> {
+    program: Cow<'p, [Instruction]>,
+    options: SearchOptions,
+    threads: ThreadList,
+    delayed_threads: Vec<(usize, usize, ThreadGroup)>,
+    buffers: StepBuffers,
+}
+
+impl <'p> Searcher<'p> {
+    pub fn new(program: &'p [Instruction]) -> Self {
+        let program = with_match_start(program);
+        let threads = ThreadList::new(program.len());
+        let buffers = StepBuffers::new(program.len());
+        Searcher { program, options: SearchOptions::default(), threads, delayed_threads: Vec::new(), buffers }
+    }
 
+    /// Runs each of the searches with `options` instead of the defaults.
+    pub fn with_options(mut self, options: &SearchOptions) -> Self {
+        self.options = options.clone();
+        self
+    }
+
+    /// Finds the leftmost-longest match in `input`, like `search`. If the search fails because of a
+    /// limit in the options, this returns `None`.
+    pub fn search(&mut self, input: &str) -> Option<(usize, usize)> {
+        self.search_bytes(input.as_bytes()).unwrap_or_default()
+    }
+
+    /// Like `search`, but for input that isn't necessarily UTF-8, and passes on the error when the
+    /// search fails.
+    pub fn search_bytes(&mut self, input: &[u8]) -> Result<Option<(usize, usize)>, RegexError> {
+        // A failed search can leave threads behind, so clear them out before starting.
+        self.threads.clear();
+        self.delayed_threads.clear();
+        self.buffers.temp_threads.clear();
+        self.buffers.next_threads.clear();
+        start_thread(&self.program, 0, &self.options, &mut self.threads);
+
+        let buffers = mem::replace(&mut self.buffers, StepBuffers::new(0));
+        let mut executor = Executor::with_buffers(&self.program, input, &self.options, buffers);
+        executor.delayed_threads = mem::take(&mut self.delayed_threads);
+        let all_matches = executor.run(&mut self.threads, 0);
+        self.delayed_threads = executor.delayed_threads;
+        self.buffers = executor.buffers;
+        Ok(all_matches?.iter().map(|(_, span)| span).fold(None, leftmost_longer_match))
     }
 }
 
-pub fn search(prog: &[Instruction], input: &str) -> Option<(usize, usize)> {
-    let mut executor = Executor::new(prog);
-    let mut current_threads = ThreadList::new(prog.len());
-    current_threads.add_thread(0, ThreadGroup::new(0));
-    executor.run(&mut current_threads, input)
+/// An iterator over the successive non-overlapping matches of a program in some text.
+///
+/// Each call to `next` runs one more search, so matches are only computed as they are needed. If
+/// one of those searches fails because of a limit in its options, the iterator just ends.
+pub struct Matches<'p, 't> {
+    program: &'p [Instruction],
+    text: &'t [u8],
+    cursor: usize,
+    options: SearchOptions,
+}
+
+impl <'p, 't> Matches<'p, 't> {
+    pub fn new(program: &'p [Instruction], text: &'t str) -> Self {
+        Matches::from_bytes(program, text.as_bytes())
+    }
+
+    pub fn from_bytes(program: &'p [Instruction], text: &'t [u8]) -> Self {
+        Matches { program, text, cursor: 0, options: SearchOptions::default() }
+    }
+
+    /// Runs each of the searches with `options` instead of the defaults.
+    pub fn with_options(mut self, options: &SearchOptions) -> Self {
+        self.options = options.clone();
+        self
+    }
+}
+
+impl <'p, 't> Iterator for Matches<'p, 't> {
+    type Item = (usize, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.cursor > self.text.len() {
+            return None;
+        }
+
+        let (start, end) = search_bytes_at(self.program, self.text, self.cursor, &self.options)?;
+        // An empty match would be found again at the same spot, so step past it.
+        self.cursor = if start == end { end + 1 } else { end };
+        Some((start, end))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::regex;
+
+    #[test]
+    fn merged_threads_find_the_same_matches() {
+        let cases = [
+            ("x+y", "xxxxy xy"),
+            ("a|ab|abc", "zabcabc"),
+            ("(a|b)*c", "ababbcabc"),
+            ("[a-c]+x(\\d+)z", "abcab x12z abx3z"),
+            ("b*", "aaa"),
+            ("(a*)*b", "aaaaab"),
+        ];
+        for (pattern, input) in cases {
+            let prog = regex::compile(pattern).unwrap();
+            // `search_overlapping` doesn't merge threads, and its first match is the leftmost-longest.
+            let expected = search_overlapping(&prog, input).into_iter().next();
+            assert_eq!(search(&prog, input), expected, "{pattern} on {input:?}");
+        }
+    }
+
+    #[test]
+    fn long_lines_are_searched_in_linear_time() {
+        let prog = regex::compile("x+y").unwrap();
+        let mut line = "x".repeat(100_000);
+        let (result, stats) = search_with_stats(&prog, &line);
+        assert_eq!(result, None);
+        // Without merging, every offset would keep a thread alive and this would be in the billions.
+        assert!(stats.steps < 10 * line.len() as u64, "{} steps", stats.steps);
+
+        line.push('y');
+        assert_eq!(search(&prog, &line), Some((0, line.len())));
+    }
+
+    #[test]
+    fn search_last_merges_onto_the_latest_start() {
+        let cases = [
+            ("x+y", "xxxxy xy"),
+            ("a|ab|abc", "zabcabc"),
+            ("(a|b)*c", "ababbcabc"),
+            ("[a-c]+x(\\d+)z", "abcab x12z abx3z"),
+            ("b*", "aaa"),
+            ("(a*)*b", "aaaaab"),
+            ("(\\w)\\1", "aabbxcc"),
+        ];
+        for (pattern, input) in cases {
+            let prog = regex::compile(pattern).unwrap();
+            // The last of the overlapping matches starts furthest right, and it's the longest there.
+            let expected = search_overlapping(&prog, input).pop();
+            assert_eq!(search_last(&prog, input), expected, "{pattern} on {input:?}");
+        }
+
+        let prog = regex::compile("x+y").unwrap();
+        let line = "x".repeat(100_000);
+        let run = Run { rightmost: true, ..Run::default() };
+        let (_, stats) = run_executor(&prog, line.as_bytes(), 0, &SearchOptions::default(), run).unwrap();
+        assert!(stats.steps < 10 * line.len() as u64, "{} steps", stats.steps);
+    }
+
+    #[test]
+    fn max_threads_counts_each_pc_once() {
+        let literal = regex::compile("abc").unwrap();
+        let nested = regex::compile("(a*)*b").unwrap();
+        let (_, literal_stats) = search_with_stats(&literal, "aaaaab");
+        let (_, nested_stats) = search_with_stats(&nested, "aaaaab");
+        assert!(nested_stats.max_threads > literal_stats.max_threads);
+        // There can't be more active pcs than instructions.
+        assert!(nested_stats.max_threads <= nested.len());
+    }
+
+    #[test]
+    fn profile_spikes_once_the_nested_quantifier_starts() {
+        let prog = regex::compile("x(a*)*b").unwrap();
+        let (result, profile) = search_profiled(&prog, "zzzxaaab");
+        assert_eq!(result, Some((3, 8)));
+        // One entry for each byte, plus the end of the input.
+        assert_eq!(profile.len(), 9);
+        // Only the search prefix and the `x` are live until the `x` is consumed at byte 3.
+        assert!(profile[..4].iter().all(|threads| *threads <= profile[0] + 1));
+        assert!(profile[4] > 2 * profile[3]);
+        let peak = profile.iter().max().unwrap();
+        assert!(profile[4..8].contains(peak));
+        assert!(*peak <= prog.len());
+    }
+
+    #[test]
+    fn reused_searcher_matches_fresh_searches() {
+        let prog = regex::compile("a+b|c").unwrap();
+        let inputs = ["xaab", "", "ccc", "aaaa", "b", "zzaabzzc"];
+        let mut searcher = Searcher::new(&prog);
+        for input in inputs {
+            assert_eq!(searcher.search(input), search(&prog, input), "{input:?}");
+        }
+
+        // A search that fails partway through mustn't leave anything behind for the next one.
+        let options = SearchOptions { max_threads: Some(1), ..SearchOptions::default() };
+        let mut limited = Searcher::new(&prog).with_options(&options);
+        assert!(limited.search_bytes(b"aaaaab").is_err());
+        let mut searcher = limited.with_options(&SearchOptions::default());
+        for input in inputs {
+            assert_eq!(searcher.search(input), search(&prog, input), "{input:?}");
+        }
+    }
+
+    #[test]
+    fn find_iter_finds_every_match_on_a_line() {
+        let re = regex::Regex::new(regex::compile("a+b").unwrap());
+        let text = "ab xaab aaab";
+        let found: Vec<_> = re.find_iter(text).collect();
+        assert_eq!(found, [(0, 2), (4, 7), (8, 12)]);
+        assert_eq!(found, search_all(re.program(), text));
+    }
+
+    #[test]
+    fn jumps_past_the_end_are_dead_threads() {
+        let jump = Instruction::Branch { c_min: 0, c_max: u8::MAX, dest: 99, consume: false, inverted: false };
+        let prog = [Instruction::Save(0, false), jump, Instruction::Save(1, true)];
+        assert_eq!(search(&prog, "abc"), None);
+        assert_eq!(search(&prog, ""), None);
+
+        // The other side of the split still gets to match.
+        let prog = [
+            Instruction::Save(0, false),
+            Instruction::Split(50, 2),
+            Instruction::Byte(b'a'),
+            Instruction::Save(1, true),
+        ];
+        assert_eq!(search(&prog, "ab"), Some((0, 1)));
+    }
+
+    #[test]
+    fn too_many_threads_give_up_the_search() {
+        let prog = regex::compile("(a|ab)(c|bcd)(d*)").unwrap();
+        let input = "xxabcdddd";
+        let limited = SearchOptions { max_threads: Some(2), ..SearchOptions::default() };
+        let result = search_with(&prog, input, &limited);
+        assert!(matches!(result, Err(RegexError::ThreadLimitExceeded { limit: 2 })), "{result:?}");
+
+        let generous = SearchOptions { max_threads: Some(prog.len()), ..SearchOptions::default() };
+        assert_eq!(search_with(&prog, input, &generous).unwrap(), search(&prog, input));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn slow_searches_time_out() {
+        // The backreference stops threads from merging, so this is quadratic in the input.
+        let prog = regex::compile("(a*)\\1b").unwrap();
+        let input = "a".repeat(20_000);
+        let options = SearchOptions { timeout: Some(Duration::from_millis(1)), ..SearchOptions::default() };
+        let result = search_with(&prog, &input, &options);
+        assert!(matches!(result, Err(RegexError::Timeout)), "{result:?}");
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn fast_searches_never_time_out() {
+        // The clock isn't checked until thousands of steps in, so even no time at all is enough.
+        let prog = regex::compile("abc").unwrap();
+        let options = SearchOptions { timeout: Some(Duration::ZERO), ..SearchOptions::default() };
+        assert_eq!(search_with(&prog, "xxabcxx", &options).unwrap(), Some((2, 5)));
+    }
+
+    #[test]
+    fn search_last_finds_the_rightmost_start() {
+        let prog = regex::compile("ab+").unwrap();
+        assert_eq!(search(&prog, "abb xabbb"), Some((0, 3)));
+        assert_eq!(search_last(&prog, "abb xabbb"), Some((5, 9)));
+
+        // Every offset inside a run of `a`s starts a match, so the last one is a single byte.
+        let prog = regex::compile("a+").unwrap();
+        assert_eq!(search(&prog, "baaa"), Some((1, 4)));
+        assert_eq!(search_last(&prog, "baaa"), Some((3, 4)));
+        assert_eq!(search_last(&prog, "bbb"), None);
+    }
+
+    #[test]
+    // The lint already knows the input isn't UTF-8, but the test is only worth anything while that
+    // stays true.
+    #[allow(invalid_from_utf8)]
+    fn bytes_search_past_invalid_utf8() {
+        let prog = regex::compile("ab+").unwrap();
+        let input = b"\xff\xfe x\xc3abb\x80";
+        assert!(str::from_utf8(input).is_err());
+        assert_eq!(search_bytes(&prog, input), Some((5, 8)));
+        assert_eq!(search_all_bytes(&prog, input), [(5, 8)]);
+    }
+
+    #[test]
+    fn empty_inputs_and_empty_matches() {
+        assert_eq!(search(&regex::compile("a").unwrap(), ""), None);
+        assert_eq!(search(&regex::compile("a*").unwrap(), ""), Some((0, 0)));
+        assert_eq!(search(&regex::compile("").unwrap(), ""), Some((0, 0)));
+        assert_eq!(search(&regex::compile("x*").unwrap(), "abc"), Some((0, 0)));
+        assert_eq!(search(&[Instruction::Save(1, true)], ""), Some((0, 0)));
+        assert_eq!(search(&[Instruction::Save(1, true)], "abc"), Some((0, 0)));
+    }
+
+    #[test]
+    fn search_multi_reports_which_pattern_matched() {
+        let progs: Vec<_> = ["foo", "ba+r", "baz"].iter().map(|p| regex::compile(p).unwrap()).collect();
+        assert_eq!(search_multi(&progs, "xx baaar"), Some((1, (3, 8))));
+        assert_eq!(search_multi(&progs, "a baz and a foo"), Some((2, (2, 5))));
+        assert_eq!(search_multi(&progs, "nothing"), None);
+    }
+
+    #[test]
+    fn overlapping_matches_include_every_start() {
+        let prog = regex::compile("aa").unwrap();
+        assert_eq!(search_overlapping(&prog, "aaaa"), [(0, 2), (1, 3), (2, 4)]);
+        assert_eq!(search_all(&prog, "aaaa"), [(0, 2), (2, 4)]);
+    }
+
+    #[test]
+    fn programs_without_a_start_save_report_where_the_match_starts() {
+        // .*?b, with no `Save(0)` after the search prefix.
+        let prog = [
+            Instruction::Split(3, 1),
+            Instruction::Branch { c_min: 0, c_max: u8::MAX, dest: 0, consume: true, inverted: false },
+            Instruction::Branch { c_min: 0, c_max: u8::MAX, dest: 0, consume: false, inverted: false },
+            Instruction::Byte(b'b'),
+            Instruction::Save(1, true),
+        ];
+        assert_eq!(search(&prog, "aab"), Some((2, 3)));
+        assert_eq!(search(&prog, "xxxxbb"), Some((4, 5)));
+    }
+
+    #[test]
+    fn search_options_combine() {
+        let prog = regex::compile("ab+").unwrap();
+        for input in ["abb", "xxabb", "ABB", "xABb", ""] {
+            assert_eq!(search_with(&prog, input, &SearchOptions::default()).unwrap(), search(&prog, input));
+        }
+
+        let anchored = SearchOptions { anchored: true, ..SearchOptions::default() };
+        let insensitive = SearchOptions { case_insensitive: true, ..SearchOptions::default() };
+        let both = SearchOptions { anchored: true, case_insensitive: true, ..SearchOptions::default() };
+        let cases = [
+            ("abb", Some((0, 3)), Some((0, 3)), Some((0, 3))),
+            ("xxabb", None, Some((2, 5)), None),
+            ("ABbB", None, Some((0, 4)), Some((0, 4))),
+            ("xABb", None, Some((1, 4)), None),
+        ];
+        for (input, expected_anchored, expected_insensitive, expected_both) in cases {
+            assert_eq!(search_with(&prog, input, &anchored).unwrap(), expected_anchored, "{input:?}");
+            assert_eq!(search_with(&prog, input, &insensitive).unwrap(), expected_insensitive, "{input:?}");
+            assert_eq!(search_with(&prog, input, &both).unwrap(), expected_both, "{input:?}");
+        }
+    }
+
+    #[test]
+    fn is_match_agrees_with_search() {
+        let patterns = ["abc", "a+b", "(a|b)*c", "^x", "y$", "\\bfoo\\b", "(\\w)\\1", "[^a-z]+", "", "q?"];
+        let inputs = ["", "abc", "aab", "xay", "a foo b", "food", "hello", "ABC", "babc"];
+        for pattern in patterns {
+            let prog = regex::compile(pattern).unwrap();
+            for input in inputs {
+                assert_eq!(is_match(&prog, input), search(&prog, input).is_some(), "{pattern:?} on {input:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn fail_ends_the_thread() {
+        let prog = [Instruction::Save(0, false), Instruction::Fail, Instruction::Save(1, true)];
+        assert_eq!(search(&prog, ""), None);
+        assert_eq!(search(&prog, "abc"), None);
+
+        // A split with one side that fails is the same as just the other side.
+        let alone = [Instruction::Save(0, false), Instruction::Byte(b'a'), Instruction::Save(1, true)];
+        let split = [
+            Instruction::Save(0, false),
+            Instruction::Split(2, 4),
+            Instruction::Byte(b'a'),
+            Instruction::Save(1, true),
+            Instruction::Fail,
+        ];
+        for input in ["a", "ab", "b", ""] {
+            assert_eq!(search(&split, input), search(&alone, input), "{input:?}");
+        }
+    }
+
+    #[test]
+    fn multiline_anchors_match_at_line_breaks() {
+        let prog = regex::compile("^foo").unwrap();
+        let input = "a foo\nfoo bar";
+        let multiline = SearchOptions { multiline: true, ..SearchOptions::default() };
+        assert_eq!(search(&prog, input), None);
+        assert_eq!(search_with(&prog, input, &multiline).unwrap(), Some((6, 9)));
+
+        let prog = regex::compile("foo$").unwrap();
+        assert_eq!(search(&prog, "a foo\nbar"), None);
+        assert_eq!(search_with(&prog, "a foo\nbar", &multiline).unwrap(), Some((2, 5)));
+    }
+
+    #[test]
+    fn the_leftmost_match_wins_over_a_longer_later_one() {
+        let prog = regex::compile("abc|abcd").unwrap();
+        assert_eq!(search(&prog, "xxabcabcd"), Some((2, 5)));
+        assert_eq!(search(&prog, "xxabcdabc"), Some((2, 6)));
+    }
+
+    #[test]
+    fn super_linear_searches_hit_the_step_limit() {
+        let options = SearchOptions { max_steps_per_char: Some(100), ..SearchOptions::default() };
+        let input = "a".repeat(200);
+
+        // The backreference stops threads from merging, so every start keeps its own copy of the
+        // nested loop running.
+        let nested = regex::compile("((a|b)*)*\\1c").unwrap();
+        let result = search_with(&nested, &input, &options);
+        assert!(matches!(result, Err(RegexError::ComplexityLimit { steps_per_char: 100 })), "{result:?}");
+
+        for pattern in ["a*b", "(a*)*b"] {
+            let linear = regex::compile(pattern).unwrap();
+            assert_eq!(search_with(&linear, &input, &options).unwrap(), None, "{pattern:?}");
+        }
+    }
+
+    #[test]
+    fn matches_in_numbers_lines_from_one() {
+        let prog = regex::compile("o+").unwrap();
+        let text = "foo\nbar\nboo zoo\r\n\noo";
+        let found: Vec<_> = matches_in(&prog, text).collect();
+        assert_eq!(found, [(1, 1, 3), (3, 1, 3), (3, 5, 7), (5, 0, 2)]);
+    }
+
+    #[test]
+    fn unicode_word_boundaries_treat_letters_as_word_characters() {
+        let prog = regex::compile("\\bcafé\\b").unwrap();
+        let unicode = SearchOptions { word_class: WordClass::Unicode, ..SearchOptions::default() };
+        assert_eq!(search_with(&prog, "un café ", &unicode).unwrap(), Some((3, 8)));
+        assert_eq!(search_with(&prog, "cafés", &unicode).unwrap(), None);
+        assert_eq!(search_with(&prog, "écafé", &unicode).unwrap(), None);
+
+        // With only ASCII word characters, the `é` isn't part of the word, so the `s` starts a new one.
+        assert_eq!(search(&prog, "cafés"), Some((0, 5)));
+    }
 }
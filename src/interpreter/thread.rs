@@ -1,20 +1,23 @@
-use std::collections::LinkedList;
-use std::mem;
-use std::slice;
-use std::vec;
-
-#[derive(PartialEq, Eq, Clone, Hash)]
+use alloc::collections::LinkedList;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem;
+use core::slice;
+#[cfg(not(feature = "std"))]
+use alloc::collections::BTreeSet as Set;
+#[cfg(feature = "std")]
+use std::collections::HashSet as Set;
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Hash)]
 struct ThreadData {
     // TODO: Can we make this Vec<(usize, usize)> since the indices always come in pairs?
     match_indices: Vec<usize>,
 }
 
 impl ThreadData {
-    fn new() -> Self {
+    fn new(slots: usize) -> Self {
         ThreadData {
-            // For now, we know that there will only ever be 2 indices (start and end), but this
-            // won't be true if we ever support submatches.
-            match_indices: vec![0, 0],
+            match_indices: vec![0; slots],
         }
     }
 }
@@ -22,8 +25,24 @@ impl ThreadData {
 
 type StoredThreadData = (usize, LinkedList<ThreadData>);
 
+/// Which threads survive when there are several at the same instruction.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Merge {
+    /// Keep every thread with different saved indices.
+    None,
+    /// Keep only the thread whose match started first, for the leftmost match.
+    Earliest,
+    /// Keep only the thread whose match started last, for the rightmost match.
+    Latest,
+}
+
+#[derive(Default)]
 pub struct ThreadList {
     threads: Vec<StoredThreadData>,
+    /// Where each pc's group is in `threads`. Entries for pcs that aren't in the list can hold
+    /// anything, so an entry only counts if the group it points to really is at that pc. That way
+    /// clearing the list doesn't have to touch this at all.
+    index: Vec<usize>,
 }
 
 pub struct ThreadListIterMut<'a> {
@@ -31,15 +50,23 @@ pub struct ThreadListIterMut<'a> {
 }
 
 impl ThreadList {
+    /// Creates an empty list for a program with `capacity` instructions.
     pub fn new(capacity: usize) -> Self {
-        ThreadList { threads: Vec::with_capacity(capacity) }
+        ThreadList { threads: Vec::with_capacity(capacity), index: vec![0; capacity] }
     }
 
+    /// Adds the threads to the group at `pc`. Threads at a pc past the end of the program are dead,
+    /// so they're dropped.
     pub fn add_thread(&mut self, pc: usize, mut thread_data: ThreadGroup) {
-        if let Some((_, data)) = self.threads.iter_mut().find(|(stored_pc, _)| {*stored_pc == pc}) {
-            data.append(&mut thread_data.data);
-        } else {
-            self.threads.push((pc, thread_data.data));
+        let Some(&index) = self.index.get(pc) else {
+            return;
+        };
+        match self.threads.get_mut(index) {
+            Some((stored_pc, data)) if *stored_pc == pc => data.append(&mut thread_data.data),
+            _ => {
+                self.index[pc] = self.threads.len();
+                self.threads.push((pc, thread_data.data));
+            }
         }
     }
 
@@ -47,7 +74,7 @@ impl ThreadList {
         self.threads.clear()
     }
 
-    pub fn iter_mut(&mut self) -> ThreadListIterMut {
+    pub fn iter_mut(&mut self) -> ThreadListIterMut<'_> {
         ThreadListIterMut { iter: self.threads.iter_mut() }
     }
 
@@ -55,6 +82,11 @@ impl ThreadList {
         self.threads.is_empty()
     }
 
+    /// The number of individual threads across all of the groups in the list.
+    pub fn thread_count(&self) -> usize {
+        self.threads.iter().map(|(_, data)| data.len()).sum()
+    }
+
 }
 
 /// A group of threads that are all at the same execution point in the program.
@@ -65,19 +97,56 @@ pub struct ThreadGroup {
 }
 
 impl ThreadGroup {
-    pub fn new(pc: usize) -> Self {
+    /// Creates a single thread with room to save `slots` indices.
+    pub fn new(pc: usize, slots: usize) -> Self {
         ThreadGroup {
-            pc: pc,
-            data: LinkedList::from([ThreadData::new()]),
+            pc,
+            data: LinkedList::from([ThreadData::new(slots)]),
         }
     }
 
+    /// Breaks the group apart into one group per thread.
+    pub fn split_threads(self) -> Vec<ThreadGroup> {
+        let pc = self.pc;
+        self.data.into_iter()
+            .map(|data| ThreadGroup { pc, data: LinkedList::from([data]) })
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// The number of threads in the group.
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
     pub fn save(&mut self, match_index: usize, char_index: usize) {
         for thread_data in self.data.iter_mut() {
             thread_data.match_indices[match_index] = char_index;
         }
     }
 
+    /// Drops every thread but the one whose match started first, or last for `Merge::Latest`.
+    /// Threads at the same pc all do the same thing from here on, so when nothing but the start of
+    /// the match is needed, that's the only one worth keeping.
+    pub fn keep_one(&mut self, merge: Merge) {
+        if self.data.len() > 1 {
+            let threads = mem::take(&mut self.data).into_iter();
+            let kept = match merge {
+                Merge::Latest => threads.max_by_key(|data| data.match_indices[0]),
+                Merge::None | Merge::Earliest => threads.min_by_key(|data| data.match_indices[0]),
+            };
+            self.data.extend(kept);
+        }
+    }
+
+    /// Where the match started for the first thread in the group.
+    fn start(&self) -> usize {
+        self.data.front().map_or(0, |data| data.match_indices[0])
+    }
+
     pub fn get_match_data(&self, match_index: usize) -> Vec<(usize, usize)> {
         let mut char_indices = Vec::with_capacity(self.data.len());
         for data in self.data.iter() {
@@ -88,16 +157,90 @@ impl ThreadGroup {
 
 }
 
+/// Remembers which threads have already run each instruction for the current input character.
+///
+/// Threads with the same saved indices at the same instruction will do exactly the same thing from
+/// then on, so only the first copy needs to run. This also stops loops that don't consume anything
+/// (like `(a*)*`) from going around forever, since the threads are the same the next time around.
+///
+/// When only the start of the match matters, the threads are merged down to one per instruction
+/// instead, and this only has to remember the best start that has run at each pc.
+#[derive(Default)]
+pub struct VisitedSet {
+    /// The pcs that have run, along with the best start of the threads that ran there.
+    dense: Vec<(usize, usize)>,
+    /// Where each pc is in `dense`, with the same trick as `ThreadList::index`.
+    sparse: Vec<usize>,
+    /// Every thread that has run, for when threads can't be merged. This is a hash set when there's
+    /// `std` to get one from, or an ordered set when there's only `alloc`.
+    seen: Set<(usize, ThreadData)>,
+}
+
+impl VisitedSet {
+    /// Creates an empty set for a program with `capacity` instructions.
+    pub fn new(capacity: usize) -> Self {
+        VisitedSet { dense: Vec::with_capacity(capacity), sparse: vec![0; capacity], seen: Set::default() }
+    }
+
+    /// Drops the threads in the group that have already been at its pc and marks the rest.
+    ///
+    /// When threads are merged, the group is cut down to its one best thread first, and that only
+    /// survives if it started before (or for `Merge::Latest`, after) every thread that has already
+    /// run at the pc. A later thread can get to a pc by a longer path than an earlier one, so a pc
+    /// can run more than once, but only when the start improves, which can't go on forever.
+    pub fn filter(&mut self, thread_group: &mut ThreadGroup, merge: Merge) {
+        let pc = thread_group.pc;
+        if merge != Merge::None {
+            thread_group.keep_one(merge);
+            let start = thread_group.start();
+            let improves = |best: usize| if merge == Merge::Latest { start > best } else { start < best };
+            match self.position(pc) {
+                Some(index) if !improves(self.dense[index].1) => thread_group.data.clear(),
+                Some(index) => self.dense[index].1 = start,
+                None => self.insert(pc, start),
+            }
+            return;
+        }
+
+        thread_group.data = mem::take(&mut thread_group.data).into_iter()
+            .filter(|data| self.seen.insert((pc, data.clone())))
+            .collect();
+        if !thread_group.is_empty() && self.position(pc).is_none() {
+            self.insert(pc, thread_group.start());
+        }
+    }
+
+    /// The number of different pcs that threads have run at.
+    pub fn len(&self) -> usize {
+        self.dense.len()
+    }
+
+    /// Forgets every thread, keeping the memory around for the next input character.
+    pub fn clear(&mut self) {
+        self.dense.clear();
+        self.seen.clear();
+    }
+
+    fn position(&self, pc: usize) -> Option<usize> {
+        let index = *self.sparse.get(pc)?;
+        self.dense.get(index).filter(|(stored_pc, _)| *stored_pc == pc).map(|_| index)
+    }
+
+    fn insert(&mut self, pc: usize, start: usize) {
+        if let Some(entry) = self.sparse.get_mut(pc) {
+            *entry = self.dense.len();
+            self.dense.push((pc, start));
+        }
+    }
+}
+
 impl <'a> Iterator for ThreadListIterMut<'a> {
     type Item = ThreadGroup;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.iter.next() {
-            None => None,
-            Some((pc, data)) => Some(ThreadGroup {
-                pc: *pc,
-                data: mem::take(data),
-            }),
-        }
+        self.iter.next().map(|(pc, data)| ThreadGroup {
+            pc: *pc,
+            data: mem::take(data),
+        })
     }
 }
\ No newline at end of file
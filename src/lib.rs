@@ -0,0 +1,37 @@
+// Everything but reading files and timing searches only needs `alloc`, so the library can be built
+// without the `std` feature for targets that don't have it.
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+pub mod error;
+pub mod interpreter;
+pub mod regex;
+
+#[cfg(all(test, not(feature = "std")))]
+mod tests {
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    use crate::interpreter;
+    use crate::regex::{self, Instruction};
+
+    #[test]
+    fn searches_a_program_built_in_memory() {
+        // ab+
+        let prog: Vec<Instruction> = vec![
+            Instruction::Save(0, false),
+            Instruction::Byte(b'a'),
+            Instruction::Byte(b'b'),
+            Instruction::Split(2, 4),
+            Instruction::Save(1, true),
+        ];
+        assert_eq!(interpreter::search(&prog, "abbbc"), Some((0, 4)));
+        assert_eq!(interpreter::search(&prog, "ac"), None);
+
+        let compiled = regex::compile("ab+").unwrap();
+        assert_eq!(interpreter::search(&compiled, "xxabbc"), Some((2, 5)));
+        let encoded = regex::bin::encode(&compiled).unwrap();
+        assert_eq!(regex::bin::parse_bytes(&encoded).unwrap(), compiled);
+    }
+}
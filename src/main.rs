@@ -1,41 +1,521 @@
-mod interpreter;
-mod regex;
-
 use std::env;
 use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
 use std::process;
+use std::str;
 use std::time;
 
+use regex_demo::{interpreter, regex};
+use regex_demo::error::RegexError;
+use regex_demo::interpreter::{Fold, Matches, SearchOptions, SearchStats, WordClass};
+use regex_demo::regex::{Instruction, PatternSet};
+use regex_demo::regex::bin::ParseOptions;
+
+const USAGE_FLAGS: &str = "[-c] [--column] [--count-matches] [-i] [-l] [-n] [-o] [-r] [-v] [-x] \
+    [--anchored] [--binary] [--dot] [--fold <ascii|unicode>] [--max-threads <n>] \
+    [--max-steps-per-char <n>] [--profile] [--timeout <ms>] [--skip-checksum] [--stats] [--strict] \
+    [--trace] [--word-class <ascii|unicode>]";
+const USAGE_PATTERNS: &str = "<regex_file> <text_file>... | -e <pattern> <text_file>... \
+    | --patterns <pattern_file> <text_file>...";
+
+/// The name of a file that was searched, along with its selected lines and their line numbers.
+type FileMatches<'a> = (&'a str, Vec<(usize, &'a [u8])>);
+
+#[derive(Default)]
+struct Config {
+    regex_file: String,
+    /// A pattern to compile and search for instead of reading a program from `regex_file`.
+    pattern: Option<String>,
+    /// A file of patterns to compile and search for all at once, one per line.
+    patterns_file: Option<String>,
+    /// The files to search, in the order they were given.
+    text_files: Vec<String>,
+    /// Search every file under any directories in `text_files`.
+    recursive: bool,
+    /// Search the file as raw bytes instead of requiring it to be UTF-8.
+    binary: bool,
+    /// Only print the number of selected lines.
+    count: bool,
+    /// Only print the total number of matches, which can be more than the number of lines since
+    /// a line can have several matches in it. These are the same matches that `-o` prints.
+    count_matches: bool,
+    /// Only print the names of the files that have a selected line.
+    files_with_matches: bool,
+    /// Prefix each line of output with its line number.
+    line_number: bool,
+    /// Prefix each line of output with the 1-based byte column where its match starts.
+    column: bool,
+    /// Print each match on its own line instead of the whole line it was in.
+    only_matching: bool,
+    /// Select the lines that don't match instead of the ones that do.
+    invert: bool,
+    /// Only count a match if it covers the whole line.
+    line_regexp: bool,
+    stats: bool,
+    /// Print every instruction that runs while searching each line to stderr.
+    trace: bool,
+    /// Print the most threads that were active at once while searching each line, and where, to
+    /// stderr.
+    profile: bool,
+    /// Print the program as a Graphviz graph instead of searching with it.
+    dot: bool,
+    /// Refuse to run programs with lints instead of just warning about them, and reject programs
+    /// from `.bin` files that fail `validate`.
+    strict: bool,
+    /// Load `.bin` files even if their checksum doesn't match.
+    skip_checksum: bool,
+    /// The settings for searching each line, which most of the search flags map onto.
+    options: SearchOptions,
+}
+
+impl Config {
+    fn parse(args: &[String]) -> Result<Config, String> {
+        let mut config = Config::default();
+        let mut positional = Vec::new();
+
+        let mut args = args[1..].iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-e" => match args.next() {
+                    Some(pattern) => config.pattern = Some(pattern.clone()),
+                    None => return Err("Expected a pattern after '-e'".to_string()),
+                },
+                "--patterns" => match args.next() {
+                    Some(file) => config.patterns_file = Some(file.clone()),
+                    None => return Err("Expected a file after '--patterns'".to_string()),
+                },
+                "--anchored" => config.options.anchored = true,
+                "--binary" => config.binary = true,
+                "--column" => config.column = true,
+                "--count-matches" => config.count_matches = true,
+                "--dot" => config.dot = true,
+                "--recursive" => config.recursive = true,
+                "--fold" => match args.next().map(String::as_str) {
+                    Some("ascii") => config.options.fold = Fold::Ascii,
+                    Some("unicode") => config.options.fold = Fold::Unicode,
+                    _ => return Err("Expected 'ascii' or 'unicode' after '--fold'".to_string()),
+                },
+                "--word-class" => match args.next().map(String::as_str) {
+                    Some("ascii") => config.options.word_class = WordClass::Ascii,
+                    Some("unicode") => config.options.word_class = WordClass::Unicode,
+                    _ => return Err("Expected 'ascii' or 'unicode' after '--word-class'".to_string()),
+                },
+                "--max-threads" => {
+                    let limit = number_arg(arg, args.next())?;
+                    config.options.max_threads = Some(limit as usize);
+                }
+                "--max-steps-per-char" => {
+                    config.options.max_steps_per_char = Some(number_arg(arg, args.next())?);
+                }
+                "--timeout" => {
+                    let millis = number_arg(arg, args.next())?;
+                    config.options.timeout = Some(time::Duration::from_millis(millis));
+                }
+                "--profile" => config.profile = true,
+                "--skip-checksum" => config.skip_checksum = true,
+                "--stats" => config.stats = true,
+                "--strict" => config.strict = true,
+                "--trace" => config.trace = true,
+                flag if flag.starts_with("--") => return Err(format!("Unknown option '{flag}'")),
+                flags if flags.starts_with('-') && flags.len() > 1 => {
+                    // Short flags can be bundled together, like `-vc`.
+                    for flag in flags.chars().skip(1) {
+                        match flag {
+                            'c' => config.count = true,
+                            'i' => config.options.case_insensitive = true,
+                            'l' => config.files_with_matches = true,
+                            'n' => config.line_number = true,
+                            'o' => config.only_matching = true,
+                            'r' => config.recursive = true,
+                            'v' => config.invert = true,
+                            'x' => config.line_regexp = true,
+                            _ => return Err(format!("Unknown option '-{flag}'")),
+                        }
+                    }
+                }
+                _ => positional.push(arg.clone()),
+            }
+        }
+
+        // There's no regex file when the patterns come from `-e` or `--patterns`.
+        if config.pattern.is_some() && config.patterns_file.is_some() {
+            return Err("Only one of '-e' and '--patterns' can be used".to_string());
+        }
+        let mut positional = positional.into_iter();
+        if config.pattern.is_none() && config.patterns_file.is_none() {
+            config.regex_file = positional.next().unwrap_or_default();
+        }
+        config.text_files = positional.collect();
+        // Drawing the program doesn't need anything to search.
+        if config.regex_file.is_empty() && config.pattern.is_none() && config.patterns_file.is_none() {
+            return Err("Expected a regex file".to_string());
+        }
+        if config.text_files.is_empty() && !config.dot {
+            return Err("Expected a regex file and at least one text file".to_string());
+        }
+
+        Ok(config)
+    }
+
+    /// Decides whether a line should be selected based on the result of searching it.
+    fn selects(&self, line: &[u8], result: Option<(usize, usize)>) -> bool {
+        let is_match = match result {
+            // The leftmost-longest match is found, so if any match covers the whole line then this
+            // one does.
+            Some((start, end)) if self.line_regexp => start == 0 && end == line.len(),
+            Some(_) => true,
+            None => false,
+        };
+        is_match != self.invert
+    }
+
+    /// Finds where the first match in a selected line starts, for `--column`.
+    fn first_match_start(&self, prog: &[Instruction], line: &[u8]) -> Option<usize> {
+        if self.invert {
+            None
+        } else if self.line_regexp {
+            Some(0)
+        } else {
+            Matches::from_bytes(prog, line).with_options(&self.options).next().map(|(start, _)| start)
+        }
+    }
+
+    /// Finds the parts of a selected line that count as matches for `-o` and `--count-matches`.
+    fn match_spans(&self, prog: &[Instruction], line: &[u8]) -> Vec<(usize, usize)> {
+        if self.invert {
+            // The selected lines don't have any matches in them.
+            Vec::new()
+        } else if self.line_regexp {
+            vec![(0, line.len())]
+        } else {
+            // Empty matches don't have anything to show.
+            Matches::from_bytes(prog, line).with_options(&self.options)
+                .filter(|(start, end)| start != end)
+                .collect()
+        }
+    }
+}
+
+/// Parses the number that has to follow the option `name`.
+fn number_arg(name: &str, value: Option<&String>) -> Result<u64, String> {
+    value.and_then(|value| value.parse().ok())
+        .ok_or_else(|| format!("Expected a number after '{name}'"))
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
 
-    if args.len() != 3 {
-        eprintln!("Usage: {} <regex_file> <text_file>", args[0]);
+    let config = Config::parse(&args).unwrap_or_else(|err| {
+        eprintln!("{err}");
+        eprintln!("Usage: {} {USAGE_FLAGS} {USAGE_PATTERNS}", args[0]);
         process::exit(1);
-    }
+    });
 
-    let regex_prog = regex::bin::parse_bin(&args[1]).unwrap_or_else(|err| {
+    let regex_prog = match (&config.pattern, &config.patterns_file) {
+        (Some(pattern), _) => regex::compile_with(pattern, &config.options),
+        (None, Some(patterns_file)) => compile_patterns(patterns_file, &config.options),
+        (None, None) => {
+            let options = ParseOptions { strict: config.strict, skip_checksum: config.skip_checksum };
+            regex::bin::parse_bin_with(&config.regex_file, &options)
+        }
+    };
+    let regex_prog = regex_prog.unwrap_or_else(|err| {
         eprintln!("Error parsing regex: {err}");
+        // Point out where the problem is when the pattern is right there to show.
+        if let (Some(pattern), RegexError::InvalidPattern { pos, .. }) = (&config.pattern, &err) {
+            eprintln!("    {pattern}");
+            eprintln!("    {}^", " ".repeat(pattern[..*pos].chars().count()));
+        }
         process::exit(1);
     });
 
-    let search_text = fs::read_to_string(&args[2]).unwrap_or_else(|err| {
-        eprintln!("Error reading text file: {err}");
-        process::exit(1);
-    });
+    if config.dot {
+        print!("{}", regex::bin::to_dot(&regex_prog));
+        return;
+    }
+
+    for lint in regex::validate::lint(&regex_prog) {
+        if config.strict {
+            eprintln!("Error parsing regex: {}", RegexError::Lint(lint));
+            process::exit(1);
+        }
+        eprintln!("Warning: {lint}");
+    }
+
+    let mut failed = false;
+    let mut paths = Vec::new();
+    for path in &config.text_files {
+        if config.recursive && Path::new(path).is_dir() {
+            failed |= !walk(Path::new(path), &mut paths);
+        } else {
+            paths.push(path.clone());
+        }
+    }
+
+    // Read all of the files up front so that the totals can be printed before any of the lines.
+    let texts: Vec<(&str, Vec<u8>)> = paths.iter()
+        .filter_map(|path| match read_text(path, config.binary) {
+            Ok(text) => Some((path.as_str(), text)),
+            // Anything that turns up in a directory could be a binary file, which isn't an error.
+            Err(err) if config.recursive && err.kind() == io::ErrorKind::InvalidData => None,
+            Err(err) => {
+                eprintln!("Error reading text file {path}: {err}");
+                failed = true;
+                None
+            }
+        })
+        .collect();
+
+    // Lines that are missing a literal the pattern needs can't match, so skip running the program.
+    // The literal check is case sensitive, so it can't be used when the search isn't. It also can't
+    // be used when the search is being traced, profiled or measured, since those report on the
+    // program actually running over every line.
+    let literal = regex::analyze::required_literal(&regex_prog)
+        .filter(|_| !config.options.case_insensitive && !config.trace && !config.profile && !config.stats);
 
     let start = time::SystemTime::now();
-    let mut matches: Vec<&str> = Vec::new();
-    for line in search_text.lines() {
-        if let Some((_start, _end)) = interpreter::search(&regex_prog, line) {
-            //println!("Matched '{}' in '{line}'", &line[start..end]);
-            matches.push(line);
+    let mut files: Vec<FileMatches> = Vec::new();
+    let mut stats = SearchStats::default();
+    for (path, text) in &texts {
+        let mut selected = Vec::new();
+        for (number, line) in lines(text).into_iter().enumerate() {
+            let is_selected = match &literal {
+                Some(literal) if !regex::analyze::contains(line, literal) => config.selects(line, None),
+                _ if config.trace => {
+                    let mut trace = Vec::new();
+                    let result = interpreter::search_bytes_traced(&regex_prog, line, &config.options, &mut trace);
+                    for event in trace {
+                        eprintln!("{path}:{}: {event}", number + 1);
+                    }
+                    config.selects(line, result.unwrap_or_else(|err| search_failed(path, number + 1, err)))
+                }
+                _ if config.profile => {
+                    let mut profile = Vec::new();
+                    let result = interpreter::search_bytes_profiled(&regex_prog, line, &config.options, &mut profile);
+                    // The earliest position wins a tie.
+                    let peak = profile.iter().enumerate().rev().max_by_key(|(_, threads)| **threads);
+                    if let Some((position, threads)) = peak {
+                        eprintln!("{path}:{}: peak of {threads} threads at byte {position}", number + 1);
+                    }
+                    config.selects(line, result.unwrap_or_else(|err| search_failed(path, number + 1, err)))
+                }
+                // `-x` needs to know where the match is, and `--stats` reports on the full search.
+                _ if !config.line_regexp && !config.stats => {
+                    let is_match = interpreter::is_match_bytes_with(&regex_prog, line, &config.options)
+                        .unwrap_or_else(|err| search_failed(path, number + 1, err));
+                    is_match != config.invert
+                }
+                _ => {
+                    let (result, line_stats) = interpreter::search_bytes_with(&regex_prog, line, &config.options)
+                        .unwrap_or_else(|err| search_failed(path, number + 1, err));
+                    stats.merge(&line_stats);
+                    config.selects(line, result)
+                }
+            };
+            if is_selected {
+                selected.push((number + 1, line));
+                // One line is enough to list the file, so don't bother with the rest.
+                if config.files_with_matches {
+                    break;
+                }
+            }
         }
+        files.push((path, selected));
     }
     let end = start.elapsed().unwrap();
 
-    println!("{} matches in {} s", matches.len(), (end.as_micros() as f64 / 1_000_000.0));
-    for line in matches {
-        println!("{}", line);
+    if config.files_with_matches {
+        // Only the names are printed so that the output can be passed straight to other tools.
+        for (path, selected) in &files {
+            if !selected.is_empty() {
+                println!("{path}");
+            }
+        }
+    } else {
+        print_matches(&config, &regex_prog, &files, &stats, end);
+    }
+    if failed {
+        process::exit(1);
+    }
+}
+
+fn search_failed(path: &str, number: usize, err: RegexError) -> ! {
+    eprintln!("Error searching line {number} of {path}: {err}");
+    process::exit(1);
+}
+
+fn print_matches(
+        config: &Config,
+        regex_prog: &[Instruction],
+        files: &[FileMatches],
+        stats: &SearchStats,
+        elapsed: time::Duration) {
+    // The total number of matches is the whole output, so it shouldn't come after a different total.
+    if !config.count_matches {
+        let total: usize = files.iter().map(|(_, selected)| selected.len()).sum();
+        println!("{} matches in {} s", total, (elapsed.as_micros() as f64 / 1_000_000.0));
+    }
+    if config.stats {
+        println!(
+            "{} steps, {} max threads, {} chars scanned",
+            stats.steps, stats.max_threads, stats.chars_scanned);
+    }
+    if config.count_matches {
+        let total: usize = files.iter()
+            .flat_map(|(_, selected)| selected)
+            .map(|(_, line)| config.match_spans(regex_prog, line).len())
+            .sum();
+        println!("{total}");
+        return;
+    }
+    if config.count {
+        return;
+    }
+    let mut stdout = io::stdout().lock();
+    for (path, selected) in files {
+        for &(number, line) in selected {
+            let written = if config.only_matching {
+                config.match_spans(regex_prog, line).into_iter()
+                    .try_for_each(|(start, end)| {
+                        write_line(&mut stdout, config, path, number, Some(start), &line[start..end])
+                    })
+            } else {
+                let column = config.column.then(|| config.first_match_start(regex_prog, line)).flatten();
+                write_line(&mut stdout, config, path, number, column, line)
+            };
+            if written.is_err() {
+                process::exit(1);
+            }
+        }
+    }
+}
+
+/// Writes one line of output. `column` is the byte offset where the match starts, if there is one.
+fn write_line(
+        out: &mut impl Write,
+        config: &Config,
+        path: &str,
+        number: usize,
+        column: Option<usize>,
+        text: &[u8]
+    ) -> io::Result<()> {
+    // Matches from different files would be mixed up without the name in front.
+    if config.recursive || config.text_files.len() > 1 {
+        write!(out, "{path}:")?;
+    }
+    if config.line_number {
+        write!(out, "{number}:")?;
+    }
+    if config.column && let Some(column) = column {
+        write!(out, "{}:", column + 1)?;
+    }
+    if config.binary {
+        // Write the bytes as they are so that `--binary` doesn't have to decode them.
+        out.write_all(text)?;
+    } else {
+        // A match can start or end partway through a multi-byte character, which would leave
+        // invalid UTF-8 in the output.
+        out.write_all(String::from_utf8_lossy(text).as_bytes())?;
+    }
+    out.write_all(b"\n")
+}
+
+/// Adds every file under the directory to `files`, returning false if any part of it couldn't be
+/// read.
+///
+/// The entries of each directory are sorted so that the files are always searched in the same
+/// order. Symlinks aren't followed, so a link back up the tree can't send the walk around in
+/// circles.
+fn walk(dir: &Path, files: &mut Vec<String>) -> bool {
+    let entries = fs::read_dir(dir).and_then(|entries| entries.collect::<io::Result<Vec<_>>>());
+    let mut entries = match entries {
+        Ok(entries) => entries,
+        Err(err) => {
+            eprintln!("Error reading directory {}: {err}", dir.display());
+            return false;
+        }
+    };
+    entries.sort_by_key(|entry| entry.file_name());
+
+    let mut ok = true;
+    for entry in entries {
+        let path = entry.path();
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => ok &= walk(&path, files),
+            Ok(file_type) if file_type.is_file() => files.push(path.display().to_string()),
+            // Symlinks and anything else that isn't a plain file.
+            Ok(_) => (),
+            Err(err) => {
+                eprintln!("Error reading {}: {err}", path.display());
+                ok = false;
+            }
+        }
+    }
+    ok
+}
+
+/// The first two bytes of every gzip file.
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+/// Reads a file to search, making sure it's UTF-8 unless it's going to be searched as raw bytes.
+///
+/// Gzipped files (like rotated logs) are spotted by their magic bytes rather than their extension
+/// and decompressed as they're read, so the search sees the text inside them.
+fn read_text(path: &str, binary: bool) -> io::Result<Vec<u8>> {
+    let mut reader = BufReader::new(fs::File::open(path)?);
+    let mut text = Vec::new();
+    // Without the `gzip` feature, `--binary` can still search the compressed bytes themselves.
+    let gzipped = reader.fill_buf()?.starts_with(GZIP_MAGIC);
+    if gzipped && (cfg!(feature = "gzip") || !binary) {
+        gunzip(reader)?.read_to_end(&mut text)?;
+    } else {
+        reader.read_to_end(&mut text)?;
+    }
+
+    if !binary && let Err(err) = str::from_utf8(&text) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+    }
+    Ok(text)
+}
+
+/// Wraps a gzipped file in a reader that decompresses it. Logs are sometimes several gzip members
+/// appended together, so this keeps going after the first one ends.
+#[cfg(feature = "gzip")]
+fn gunzip(reader: impl BufRead + 'static) -> io::Result<Box<dyn Read>> {
+    Ok(Box::new(flate2::bufread::MultiGzDecoder::new(reader)))
+}
+
+#[cfg(not(feature = "gzip"))]
+fn gunzip(_reader: impl BufRead + 'static) -> io::Result<Box<dyn Read>> {
+    // Searching the compressed bytes would never turn up anything, so treat it like any other file
+    // that can't be read as text.
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "file is gzipped, but regex-demo was built without the 'gzip' feature"))
+}
+
+/// Compiles every line of the file and combines them into one program that matches any of them.
+fn compile_patterns(path: &str, options: &SearchOptions) -> Result<Vec<Instruction>, RegexError> {
+    let patterns = fs::read_to_string(path)?;
+    let progs = patterns.lines()
+        .map(|pattern| regex::compile_with(pattern, options))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(PatternSet::new(&progs).program().to_vec())
+}
+
+/// Splits a file into the lines to search.
+///
+/// Text and binary files are split the same way, so that they give the same lines. A `\r` at the end
+/// of a line counts as part of the line ending, so CRLF files search the same as LF ones: `$`
+/// matches before the `\r` and it never shows up in a match. That includes a last line with no `\n`
+/// after it, which `str::lines` would leave the `\r` on.
+fn lines(text: &[u8]) -> Vec<&[u8]> {
+    let text = text.strip_suffix(b"\n").unwrap_or(text);
+    if text.is_empty() {
+        return Vec::new();
     }
+    text.split(|b| *b == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .collect()
 }
@@ -1,6 +1,21 @@
+pub mod analyze;
+pub mod asm;
 pub mod bin;
+mod compile;
+#[cfg(feature = "testutil")]
+pub mod testutil;
+pub mod validate;
 
-#[derive(PartialEq, Eq, Debug)]
+pub use compile::{compile, compile_with};
+
+use alloc::vec::Vec;
+
+#[cfg(feature = "std")]
+use crate::error::RegexError;
+use crate::interpreter;
+use crate::interpreter::Matches;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum Instruction {
     Save(usize, bool),
     Branch{
@@ -10,4 +25,194 @@ pub enum Instruction {
         consume: bool,
         inverted: bool},
     Split(usize, usize),
+    /// Consumes exactly this byte. This is the same as a consuming `Branch` with `c_min` and
+    /// `c_max` both set to the byte, but it's common enough to be worth a cheaper check.
+    Byte(u8),
+    /// Matches the exact bytes captured by the given group.
+    ///
+    /// A backreference can't be matched a byte at a time like the other instructions, so each
+    /// thread that reaches one is split off from its group and jumps ahead to wherever its copy of
+    /// the capture ends. This gives up both the linear-time guarantee and the merging of threads
+    /// that are at the same instruction, so programs with backreferences can be very slow.
+    Backref(usize),
+    /// Continues only if the position in the input passes the check, without consuming anything.
+    Assert(Assertion),
+    /// Ends the thread without a match.
+    Fail,
+    /// Consumes any byte whose bit is set in the 256-bit set, where byte `c` is bit `c % 64` of
+    /// word `c / 64`. This stands in for the chain of splits that a class with several ranges would
+    /// otherwise need.
+    ClassBitmap([u64; 4]),
+}
+
+/// The checks that an `Assert` instruction can make on the position in the input.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Assertion {
+    /// The start of the input, matched by `^`.
+    Start,
+    /// The end of the input, matched by `$`.
+    End,
+    /// The start of the input or just after a `\n`.
+    LineStart,
+    /// The end of the input or just before a `\n`.
+    LineEnd,
+    /// Between a word character and something that isn't one (including either end of the input),
+    /// matched by `\b`. `SearchOptions::word_class` decides what counts as a word character.
+    WordBoundary,
+    /// Anywhere that isn't a `WordBoundary`, matched by `\B`.
+    NotWordBoundary,
+}
+
+impl Instruction {
+    /// Moves any destination in the instruction forward by `offset`, for when the instruction is
+    /// being copied into a bigger program.
+    pub fn relocate(&self, offset: usize) -> Instruction {
+        self.map_destinations(|dest| dest + offset)
+    }
+
+    /// Replaces every destination in the instruction with the result of calling `f` on it.
+    pub fn map_destinations(&self, f: impl Fn(usize) -> usize) -> Instruction {
+        match *self {
+            Instruction::Branch{c_min, c_max, dest, consume: false, inverted} => {
+                Instruction::Branch{c_min, c_max, dest: f(dest), consume: false, inverted}
+            }
+            Instruction::Split(dest1, dest2) => Instruction::Split(f(dest1), f(dest2)),
+            inst => inst,
+        }
+    }
+}
+
+/// Builds the bitmap for a `ClassBitmap` that matches every byte in the ranges.
+pub fn class_bitmap(ranges: &[(u8, u8)]) -> [u64; 4] {
+    let mut bitmap = [0; 4];
+    for &(min, max) in ranges {
+        for c in min..=max {
+            bitmap[c as usize / 64] |= 1 << (c % 64);
+        }
+    }
+    bitmap
+}
+
+/// Checks whether byte `c` is in the set of a `ClassBitmap`.
+pub fn bitmap_contains(bitmap: &[u64; 4], c: u8) -> bool {
+    bitmap[c as usize / 64] & (1 << (c % 64)) != 0
+}
+
+/// The sorted ranges of bytes in the set of a `ClassBitmap`, which is the inverse of `class_bitmap`.
+pub fn bitmap_ranges(bitmap: &[u64; 4]) -> Vec<(u8, u8)> {
+    let mut ranges: Vec<(u8, u8)> = Vec::new();
+    for c in (0..=u8::MAX).filter(|c| bitmap_contains(bitmap, *c)) {
+        match ranges.last_mut() {
+            Some(last) if last.1 as u16 + 1 == c as u16 => last.1 = c,
+            _ => ranges.push((c, c)),
+        }
+    }
+    ranges
+}
+
+/// A compiled regex program bundled with convenience methods for searching with it.
+pub struct Regex {
+    program: Vec<Instruction>,
+}
+
+impl Regex {
+    pub fn new(program: Vec<Instruction>) -> Self {
+        Regex { program }
+    }
+
+    #[cfg(feature = "std")]
+    pub fn from_bin(path: &str) -> Result<Self, RegexError> {
+        Ok(Regex::new(bin::parse_bin(path)?))
+    }
+
+    pub fn program(&self) -> &[Instruction] {
+        &self.program
+    }
+
+    pub fn find(&self, text: &str) -> Option<(usize, usize)> {
+        interpreter::search(&self.program, text)
+    }
+
+    /// Lazily iterates over the non-overlapping matches in `text`.
+    pub fn find_iter<'p, 't>(&'p self, text: &'t str) -> Matches<'p, 't> {
+        Matches::new(&self.program, text)
+    }
+}
+
+/// Several programs joined into one so that they can all be searched for in a single pass.
+///
+/// The combined program starts with a chain of splits that fans out to each pattern's first
+/// instruction. Every match instruction stays inside its own pattern's range of pcs, which is how a
+/// match gets traced back to the pattern that made it.
+pub struct PatternSet {
+    program: Vec<Instruction>,
+    /// The pc where each pattern's instructions start, in order.
+    starts: Vec<usize>,
+}
+
+impl PatternSet {
+    pub fn new(progs: &[Vec<Instruction>]) -> Self {
+        let mut program = Vec::new();
+        let header_len = progs.len().saturating_sub(1);
+
+        let mut starts = Vec::with_capacity(progs.len());
+        let mut start = header_len;
+        for prog in progs {
+            starts.push(start);
+            start += prog.len();
+        }
+
+        // Each split tries one pattern and moves on to the next split for the rest.
+        for i in 0..header_len {
+            let rest = if i + 1 == header_len { starts[i + 1] } else { i + 1 };
+            program.push(Instruction::Split(starts[i], rest));
+        }
+        for (prog, offset) in progs.iter().zip(&starts) {
+            program.extend(prog.iter().map(|inst| inst.relocate(*offset)));
+        }
+
+        PatternSet { program, starts }
+    }
+
+    pub fn program(&self) -> &[Instruction] {
+        &self.program
+    }
+
+    /// Which of the original patterns the instruction at `pc` came from.
+    pub fn pattern_at(&self, pc: usize) -> usize {
+        self.starts.partition_point(|start| *start <= pc).saturating_sub(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bitmaps_hold_exactly_their_ranges() {
+        let ranges = [(b'0', b'0'), (b'a', b'c'), (b'x', b'z'), (0xF0, 0xFF)];
+        let bitmap = class_bitmap(&ranges);
+        for c in 0..=u8::MAX {
+            let expected = ranges.iter().any(|&(min, max)| min <= c && c <= max);
+            assert_eq!(bitmap_contains(&bitmap, c), expected, "{c:#04x}");
+        }
+        assert_eq!(bitmap_ranges(&bitmap), ranges);
+    }
+
+    #[test]
+    fn multi_range_classes_compile_to_one_bitmap() {
+        let prog = compile("[a-cx-z0]").unwrap();
+        let bitmaps: Vec<_> = prog.iter()
+            .filter_map(|inst| match inst {
+                Instruction::ClassBitmap(bitmap) => Some(bitmap),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(bitmaps.len(), 1, "{prog:?}");
+        assert_eq!(bitmap_ranges(bitmaps[0]), [(b'0', b'0'), (b'a', b'c'), (b'x', b'z')]);
+        // Nothing after the search prefix compares bytes any other way.
+        let body = &prog[analyze::skip_search_prefix(&prog)..];
+        let compares = |inst: &&Instruction| matches!(inst, Instruction::Branch{..} | Instruction::Byte(_));
+        assert_eq!(body.iter().find(compares), None, "{prog:?}");
+    }
 }
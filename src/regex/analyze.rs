@@ -0,0 +1,118 @@
+use alloc::vec::Vec;
+
+use crate::regex::Instruction;
+
+/// Finds a run of bytes that must appear in any input the program matches.
+///
+/// This only looks at the straight line of single byte comparisons at the start of the pattern
+/// (after the unanchored search prefix), since every path to a match has to go through them in
+/// order. Saves and assertions don't consume anything, so they're skipped over. Anything more complicated gives
+/// up and returns `None`.
+pub fn required_literal(prog: &[Instruction]) -> Option<Vec<u8>> {
+    let mut literal = Vec::new();
+    for inst in &prog[skip_search_prefix(prog)..] {
+        match *inst {
+            Instruction::Save(_, false) | Instruction::Assert(_) => (),
+            Instruction::Branch{c_min, c_max, consume: true, inverted: false, ..} if c_min == c_max => {
+                literal.push(c_min);
+            }
+            Instruction::Byte(c) => literal.push(c),
+            _ => break,
+        }
+    }
+
+    if literal.is_empty() {
+        None
+    } else {
+        Some(literal)
+    }
+}
+
+/// Checks whether `needle` appears anywhere in `haystack`.
+pub fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    let Some((&first, rest)) = needle.split_first() else {
+        return true;
+    };
+
+    // Jump between occurrences of the first byte rather than comparing at every offset.
+    let mut start = 0;
+    while let Some(offset) = haystack[start..].iter().position(|b| *b == first) {
+        let candidate = start + offset + 1;
+        if haystack[candidate..].starts_with(rest) {
+            return true;
+        }
+        start = candidate;
+    }
+    false
+}
+
+/// Returns the pc after the lazy `.*` loop that lets a program match anywhere in the input, or 0
+/// if the program doesn't start with one.
+pub(crate) fn skip_search_prefix(prog: &[Instruction]) -> usize {
+    match prog {
+        [
+            Instruction::Split(3, 1),
+            Instruction::Branch{c_min: 0, c_max: u8::MAX, consume: true, inverted: false, ..},
+            Instruction::Branch{c_min: 0, c_max: u8::MAX, dest: 0, consume: false, inverted: false},
+            ..
+        ] => 3,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::regex::compile;
+
+    fn literal(pattern: &str) -> Option<Vec<u8>> {
+        required_literal(&compile(pattern).unwrap())
+    }
+
+    #[test]
+    fn required_literal_stops_at_the_first_choice() {
+        assert_eq!(literal("abc"), Some(b"abc".to_vec()));
+        assert_eq!(literal("^ab(c)d"), Some(b"abcd".to_vec()));
+        assert_eq!(literal("ab+c"), Some(b"ab".to_vec()));
+        assert_eq!(literal("a?b"), None);
+        assert_eq!(literal("abc|abd"), None);
+        assert_eq!(literal(".x"), None);
+        assert_eq!(literal(""), None);
+    }
+
+    #[test]
+    fn contains_finds_needles_anywhere() {
+        assert!(contains(b"hello world", b"world"));
+        assert!(contains(b"hello world", b"hello"));
+        assert!(contains(b"aaab", b"aab"));
+        assert!(contains(b"anything", b""));
+        assert!(!contains(b"hello world", b"word"));
+        assert!(!contains(b"ab", b"abc"));
+        assert!(!contains(b"", b"a"));
+    }
+
+    #[test]
+    fn the_prefilter_never_changes_the_result() {
+        let patterns = [
+            "abc", "ab+c", "a?bc", "abc|abd", "x(ab|cd)y", "^foo", "foo$", "fo*", "(foo)?bar", "a.c",
+            "[a-c]x", "\\bword\\b", "(ab)\\1", "",
+        ];
+        let lines = [
+            "", "abc", "xabcx", "ab", "abbbc", "bc", "abd", "xcdy", "xaby", "foo", "xfoo", "f", "bar",
+            "foobar", "aXc", "bx", "a word here", "swordfish", "abab", "cba",
+        ];
+        for pattern in patterns {
+            let prog = compile(pattern).unwrap();
+            let literal = required_literal(&prog);
+            for line in lines {
+                let full = crate::interpreter::search(&prog, line);
+                // What the CLI does: skip the search for lines that are missing the literal.
+                let prefiltered = match &literal {
+                    Some(literal) if !contains(line.as_bytes(), literal) => None,
+                    _ => full,
+                };
+                assert_eq!(prefiltered, full, "{pattern:?} on {line:?} with literal {literal:?}");
+            }
+        }
+    }
+}
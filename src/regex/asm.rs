@@ -0,0 +1,249 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::error::RegexError;
+use crate::regex::{bitmap_ranges, class_bitmap, Assertion, Instruction};
+
+/// Writes a byte operand, quoting printable ASCII so listings are easier to read.
+struct Operand(u8);
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            // Quoting these would confuse `assemble`, which reads `;` as the start of a comment.
+            b'\'' | b'\\' | b';' => write!(f, "{:#04x}", self.0),
+            c if c.is_ascii_graphic() => write!(f, "'{}'", c as char),
+            c => write!(f, "{c:#04x}"),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Instruction::Save(index, false) => write!(f, "save {index}"),
+            Instruction::Save(index, true) => write!(f, "match {index}"),
+            Instruction::Branch{c_min, c_max, consume: true, inverted: false, ..} => {
+                write!(f, "range {} {}", Operand(c_min), Operand(c_max))
+            }
+            Instruction::Branch{c_min, c_max, consume: true, inverted: true, ..} => {
+                write!(f, "not {} {}", Operand(c_min), Operand(c_max))
+            }
+            Instruction::Branch{c_min: 0, c_max: u8::MAX, dest, consume: false, inverted: false} => {
+                write!(f, "jump {dest}")
+            }
+            Instruction::Branch{c_min, c_max, dest, consume: false, inverted: false} => {
+                write!(f, "branch {} {} {dest}", Operand(c_min), Operand(c_max))
+            }
+            Instruction::Branch{c_min, c_max, dest, consume: false, inverted: true} => {
+                write!(f, "branchnot {} {} {dest}", Operand(c_min), Operand(c_max))
+            }
+            Instruction::Split(dest1, dest2) => write!(f, "split {dest1} {dest2}"),
+            Instruction::Byte(c) => write!(f, "char {}", Operand(c)),
+            Instruction::Backref(group) => write!(f, "backref {group}"),
+            Instruction::Assert(Assertion::Start) => write!(f, "assert start"),
+            Instruction::Assert(Assertion::End) => write!(f, "assert end"),
+            Instruction::Assert(Assertion::LineStart) => write!(f, "assert line_start"),
+            Instruction::Assert(Assertion::LineEnd) => write!(f, "assert line_end"),
+            Instruction::Assert(Assertion::WordBoundary) => write!(f, "assert word_boundary"),
+            Instruction::Assert(Assertion::NotWordBoundary) => write!(f, "assert not_word_boundary"),
+            Instruction::Fail => write!(f, "fail"),
+            Instruction::ClassBitmap(bitmap) => {
+                write!(f, "class")?;
+                for (min, max) in bitmap_ranges(&bitmap) {
+                    if min == max {
+                        write!(f, " {}", Operand(min))?;
+                    } else {
+                        write!(f, " {}-{}", Operand(min), Operand(max))?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Lists the program one instruction per line, in the format read by `assemble`.
+///
+/// Splits that look like they came from a quantifier are labeled as greedy or lazy, based on the
+/// branch order convention described in `compile`.
+pub fn disassemble(prog: &[Instruction]) -> String {
+    let mut listing = String::new();
+    for (pc, inst) in prog.iter().enumerate() {
+        let line = match quantifier_greediness(prog, pc) {
+            Some(true) => format!("{pc}: {inst} ; greedy"),
+            Some(false) => format!("{pc}: {inst} ; lazy"),
+            None => format!("{pc}: {inst}"),
+        };
+        listing.push_str(&line);
+        listing.push('\n');
+    }
+    listing
+}
+
+/// Works out whether the split at `pc` is a greedy (`Some(true)`) or lazy (`Some(false)`) quantifier.
+///
+/// This goes by the shapes that the compiler emits: `x+` splits back to the start of its body,
+/// `x*` splits over its body and ends the body with a jump back to the split, and `x?` splits over
+/// its body. Splits that start an alternation look like `x?` except that the first alternative ends
+/// with a jump past the rest, so those are left alone.
+fn quantifier_greediness(prog: &[Instruction], pc: usize) -> Option<bool> {
+    let Instruction::Split(first, second) = prog[pc] else {
+        return None;
+    };
+
+    // x+
+    if first <= pc && second == pc + 1 {
+        return Some(true);
+    } else if second <= pc && first == pc + 1 {
+        return Some(false);
+    }
+
+    // x* and x?
+    let (exit, greedy) = if first == pc + 1 && second > first {
+        (second, true)
+    } else if second == pc + 1 && first > second {
+        (first, false)
+    } else {
+        return None;
+    };
+    match *prog.get(exit - 1)? {
+        Instruction::Branch{c_min: 0, c_max: u8::MAX, dest, consume: false, inverted: false}
+            if dest != pc && dest >= exit => None,
+        _ => Some(greedy),
+    }
+}
+
+/// Builds a program from a listing like the one produced by `disassemble`.
+///
+/// Each line holds one instruction. Anything after a `;` is a comment, and a leading `<pc>:` label
+/// is allowed but ignored. Byte operands are either quoted ASCII characters (`'a'`), quoted
+/// escapes (`'\t'`, `'\n'`, `'\r'`, `'\0'`, `'\\'`, `'\''` or `'\x61'`) or numbers (`97` or `0x61`).
+/// The operands of `class` are bytes or ranges of bytes like `'a'-'z'`.
+pub fn assemble(text: &str) -> Result<Vec<Instruction>, RegexError> {
+    let mut prog = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let error = |message: &str| RegexError::InvalidAssembly {
+            line: index + 1,
+            message: message.to_string()};
+
+        let line = line.split(';').next().unwrap_or_default();
+        let line = match line.split_once(':') {
+            Some((label, rest)) if label.trim().parse::<usize>().is_ok() => rest,
+            _ => line,
+        };
+        let mut words = line.split_whitespace();
+        let Some(mnemonic) = words.next() else {
+            continue;
+        };
+        let args: Vec<&str> = words.collect();
+
+        let inst = match (mnemonic, args.as_slice()) {
+            ("save", [index]) => Instruction::Save(number(index).ok_or_else(|| error("bad index"))?, false),
+            ("match", [index]) => Instruction::Save(number(index).ok_or_else(|| error("bad index"))?, true),
+            ("char", [c]) => Instruction::Byte(byte(c).ok_or_else(|| error("bad byte"))?),
+            ("range" | "not", [c_min, c_max]) => Instruction::Branch{
+                c_min: byte(c_min).ok_or_else(|| error("bad byte"))?,
+                c_max: byte(c_max).ok_or_else(|| error("bad byte"))?,
+                dest: 0,
+                consume: true,
+                inverted: mnemonic == "not"},
+            ("jump", [dest]) => Instruction::Branch{
+                c_min: 0,
+                c_max: u8::MAX,
+                dest: number(dest).ok_or_else(|| error("bad destination"))?,
+                consume: false,
+                inverted: false},
+            ("branch" | "branchnot", [c_min, c_max, dest]) => Instruction::Branch{
+                c_min: byte(c_min).ok_or_else(|| error("bad byte"))?,
+                c_max: byte(c_max).ok_or_else(|| error("bad byte"))?,
+                dest: number(dest).ok_or_else(|| error("bad destination"))?,
+                consume: false,
+                inverted: mnemonic == "branchnot"},
+            ("split", [dest1, dest2]) => Instruction::Split(
+                number(dest1).ok_or_else(|| error("bad destination"))?,
+                number(dest2).ok_or_else(|| error("bad destination"))?),
+            ("assert", ["start"]) => Instruction::Assert(Assertion::Start),
+            ("assert", ["end"]) => Instruction::Assert(Assertion::End),
+            ("assert", ["line_start"]) => Instruction::Assert(Assertion::LineStart),
+            ("assert", ["line_end"]) => Instruction::Assert(Assertion::LineEnd),
+            ("assert", ["word_boundary"]) => Instruction::Assert(Assertion::WordBoundary),
+            ("assert", ["not_word_boundary"]) => Instruction::Assert(Assertion::NotWordBoundary),
+            ("fail", []) => Instruction::Fail,
+            ("class", ranges) => {
+                let ranges = ranges.iter()
+                    .map(|range| byte_range(range).ok_or_else(|| error("bad range")))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Instruction::ClassBitmap(class_bitmap(&ranges))
+            }
+            ("backref", [group]) => Instruction::Backref(number(group).ok_or_else(|| error("bad group"))?),
+            _ => return Err(error(&format!("can't assemble '{}'", line.trim()))),
+        };
+        prog.push(inst);
+    }
+    Ok(prog)
+}
+
+fn number(text: &str) -> Option<usize> {
+    match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+/// Reads a single byte or a range like `'a'-'z'`. Quoted bytes can be a `-` themselves, so this
+/// tries each `-` in turn until both sides are bytes.
+fn byte_range(text: &str) -> Option<(u8, u8)> {
+    if let Some(c) = byte(text) {
+        return Some((c, c));
+    }
+    text.match_indices('-')
+        .find_map(|(index, _)| Some((byte(&text[..index])?, byte(&text[index + 1..])?)))
+        .filter(|(min, max)| min <= max)
+}
+
+fn byte(text: &str) -> Option<u8> {
+    let Some(quoted) = text.strip_prefix('\'').and_then(|rest| rest.strip_suffix('\'')) else {
+        return number(text)?.try_into().ok();
+    };
+    match quoted.as_bytes() {
+        [b'\\', b't'] => Some(b'\t'),
+        [b'\\', b'n'] => Some(b'\n'),
+        [b'\\', b'r'] => Some(b'\r'),
+        [b'\\', b'0'] => Some(b'\0'),
+        [b'\\', c @ (b'\\' | b'\'')] => Some(*c),
+        [b'\\', b'x', digits @ ..] if digits.len() == 2 && digits.iter().all(u8::is_ascii_hexdigit) => {
+            u8::from_str_radix(&quoted[2..], 16).ok()
+        }
+        [c] if c.is_ascii() => Some(*c),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::regex::compile;
+
+    fn labels(listing: &str) -> Vec<&str> {
+        listing.lines().filter_map(|line| line.split_once(" ; ").map(|(_, label)| label)).collect()
+    }
+
+    #[test]
+    fn greedy_and_lazy_stars_are_labeled_differently() {
+        let greedy = disassemble(&compile("a*").unwrap());
+        let lazy = disassemble(&compile("a*?").unwrap());
+        assert_ne!(greedy, lazy);
+        // The first split is the lazy `.*?` that lets an unanchored search start anywhere.
+        assert_eq!(labels(&greedy), ["lazy", "greedy"], "{greedy}");
+        assert_eq!(labels(&lazy), ["lazy", "lazy"], "{lazy}");
+    }
+
+    #[test]
+    fn splits_past_the_end_are_not_labeled() {
+        let listing = disassemble(&[Instruction::Split(1, 50), Instruction::Fail]);
+        assert_eq!(listing, "0: split 1 50\n1: fail\n");
+    }
+}
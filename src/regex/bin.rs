@@ -1,9 +1,16 @@
-use std::error::Error;
-use std::fmt;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::Write;
+#[cfg(feature = "std")]
 use std::fs::File;
+#[cfg(feature = "std")]
 use std::io::Read;
 
-use crate::regex::Instruction;
+use crate::error::RegexError;
+use crate::regex::{Assertion, Instruction};
+use crate::regex::validate;
 
 const OPCODE_MASK: u32 = 0xC000_0000;
 const SAVE_INDEX_MASK: u32 = 0x003F_0000;
@@ -14,6 +21,9 @@ const DEST_MASK: u32 = 0x0FFF_0000;
 const DEST2_MASK: u32 = 0x0000_FFF0;
 const CHAR_MIN_MASK: u32 = 0x0000_FF00;
 const CHAR_MAX_MASK: u32 = 0x0000_00FF;
+const EXT_OP_MASK: u32 = 0x3F00_0000;
+const WIDE_INVERTED_MASK: u32 = 0x0080_0000;
+const WIDE_DEST_MASK: u32 = 0x00FF_FFFF;
 
 const OPCODE_SHIFT: u32 = 30;
 const SAVE_INDEX_SHIFT: u32 = 16;
@@ -21,55 +31,438 @@ const DEST_SHIFT: u32 = 16;
 const DEST2_SHIFT: u32 = 4;
 const CHAR_MIN_SHIFT: u32 = 8;
 const CHAR_MAX_SHIFT: u32 = 0;
+const EXT_OP_SHIFT: u32 = 24;
 
+const OP_BRANCH: u32 = 0b00;
+const OP_SPLIT: u32 = 0b01;
+const OP_SAVE: u32 = 0b10;
+/// Instructions with this opcode use the next 6 bits to pick the actual operation.
+const OP_EXTENDED: u32 = 0b11;
 
-pub fn parse_bin(path: &str) -> Result<Vec<Instruction>, Box<dyn Error>> {
+const EXT_OP_BACKREF: u32 = 0x00;
+const EXT_OP_BYTE: u32 = 0x01;
+const EXT_OP_ASSERT: u32 = 0x02;
+const EXT_OP_FAIL: u32 = 0x03;
+/// A non-consuming `Branch` whose destination is in the next word. Only in version 2.
+const EXT_OP_WIDE_BRANCH: u32 = 0x04;
+/// A `Split` with its first destination in the low 24 bits and its second in the next word. Only
+/// in version 2.
+const EXT_OP_WIDE_SPLIT: u32 = 0x05;
+/// A `ClassBitmap`, with the 256 bits of its set in the next 8 words. Only in version 2.
+const EXT_OP_CLASS_BITMAP: u32 = 0x06;
+
+const ASSERT_START: u32 = 0x00;
+const ASSERT_END: u32 = 0x01;
+const ASSERT_LINE_START: u32 = 0x02;
+const ASSERT_LINE_END: u32 = 0x03;
+const ASSERT_WORD_BOUNDARY: u32 = 0x04;
+const ASSERT_NOT_WORD_BOUNDARY: u32 = 0x05;
+
+const INSTRUCTION_SIZE: usize = 4;
+
+/// The start of the header that versioned programs begin with. As an instruction this would have an
+/// unknown extended opcode, so it can't be mistaken for the start of a version 1 program.
+const HEADER_MAGIC: &[u8] = &[0xFF, b'R', b'X'];
+/// The magic followed by one byte for the version.
+const HEADER_SIZE: usize = 4;
+/// The CRC-32 of the instructions that follows the version from version 3 on.
+const CHECKSUM_SIZE: usize = 4;
+
+/// The versions of the bytecode format.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Version {
+    /// The original format, which has no header. Every instruction is one word, so destinations
+    /// have to fit in 12 bits, which limits jumps to the first 4096 instructions.
+    V1,
+    /// Starts with a header, and encodes jumps and splits with destinations past 4095 in two words.
+    /// Adds `ClassBitmap`, which takes up nine words.
+    V2,
+    /// Adds a CRC-32 of the instructions to the header, so that a corrupted file is caught instead of
+    /// running whatever it happens to decode to.
+    #[default]
+    V3,
+}
+
+impl Version {
+    fn number(self) -> u8 {
+        match self {
+            Version::V1 => 1,
+            Version::V2 => 2,
+            Version::V3 => 3,
+        }
+    }
+
+    /// How many bytes the header takes up before the first instruction.
+    fn header_size(self) -> usize {
+        match self {
+            Version::V1 => 0,
+            Version::V2 => HEADER_SIZE,
+            Version::V3 => HEADER_SIZE + CHECKSUM_SIZE,
+        }
+    }
+}
+
+/// Settings for how carefully a program is checked as it's parsed.
+#[derive(Clone, Default, Debug)]
+pub struct ParseOptions {
+    /// Also run `validate::validate` on the program, rejecting anything it finds.
+    pub strict: bool,
+    /// Don't check the instructions against the checksum in the header, for poking at a program
+    /// that's been edited by hand.
+    pub skip_checksum: bool,
+}
+
+#[cfg(feature = "std")]
+pub fn parse_bin(path: &str) -> Result<Vec<Instruction>, RegexError> {
+    parse_bin_with(path, &ParseOptions::default())
+}
+
+/// Like `parse_bin`, but with settings for how carefully the program is checked.
+#[cfg(feature = "std")]
+pub fn parse_bin_with(path: &str, options: &ParseOptions) -> Result<Vec<Instruction>, RegexError> {
     let mut f = File::open(path)?;
     let mut buf = Vec::new();
     f.read_to_end(&mut buf)?;
-    
+    parse_bytes_with(&buf, options)
+}
+
+/// Decodes a program in any version of the bytecode format. Programs without a header are read as
+/// version 1.
+pub fn parse_bytes(buf: &[u8]) -> Result<Vec<Instruction>, RegexError> {
+    parse_bytes_with(buf, &ParseOptions::default())
+}
+
+/// Like `parse_bytes`, but with settings for how carefully the program is checked.
+pub fn parse_bytes_with(buf: &[u8], options: &ParseOptions) -> Result<Vec<Instruction>, RegexError> {
+    let (version, expected) = parse_header(buf)?;
+    let header_size = version.header_size();
+    if let Some(expected) = expected && !options.skip_checksum {
+        let actual = crc32(&buf[header_size..]);
+        if actual != expected {
+            return Err(RegexError::ChecksumMismatch { expected, actual });
+        }
+    }
     let mut instructions = Vec::new();
 
-    // Each instruction is 32 bits
-    for chunk in buf.chunks_exact(4) {
-        let inst = parse_instruction(chunk)?;
+    let mut offset = header_size;
+    while offset < buf.len() {
+        let (inst, size) = decode_instruction(buf, offset, version)?;
         instructions.push(inst);
+        offset += size;
+    }
+    if instructions.is_empty() {
+        return Err(RegexError::EmptyProgram);
     }
 
+    check_destinations(&instructions)?;
+    if options.strict {
+        validate::validate(&instructions)?;
+    }
     Ok(instructions)
 }
 
-#[derive(Debug)]
-struct ParseError {
-    instruction: u32,
-    message: String,
+/// Reads the version from the program's header, returning it along with the checksum from the header
+/// if it has one.
+fn parse_header(buf: &[u8]) -> Result<(Version, Option<u32>), RegexError> {
+    if !buf.starts_with(HEADER_MAGIC) {
+        return Ok((Version::V1, None));
+    }
+    let version = match buf.get(HEADER_MAGIC.len()) {
+        Some(2) => Version::V2,
+        Some(3) => Version::V3,
+        Some(&version) => return Err(RegexError::UnsupportedVersion { version }),
+        None => return Err(RegexError::TruncatedInstruction { offset: 0 }),
+    };
+    if version < Version::V3 {
+        return Ok((version, None));
+    }
+    let checksum = buf.get(HEADER_SIZE..HEADER_SIZE + CHECKSUM_SIZE)
+        .ok_or(RegexError::TruncatedInstruction { offset: 0 })?;
+    Ok((version, Some(read_word(checksum))))
 }
 
-impl fmt::Display for ParseError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f, "Error parsing regex from 0x{:#10x}: {}",
-            self.instruction, self.message)
+/// The standard CRC-32 (the one used by zip and gzip) of `bytes`. Programs are small, so this goes
+/// a bit at a time rather than bothering with a lookup table.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in bytes {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
     }
+    !crc
+}
+
+fn read_word(bytes: &[u8]) -> u32 {
+    let (b1, b2, b3, b4) = (bytes[0] as u32, bytes[1] as u32, bytes[2] as u32, bytes[3] as u32);
+    b1 << 24 | b2 << 16 | b3 << 8 | b4
 }
 
-impl Error for ParseError {}
+/// Decodes the instruction that starts `offset` bytes into `buf`, returning it along with how many
+/// bytes it takes up.
+fn decode_instruction(buf: &[u8], offset: usize, version: Version) -> Result<(Instruction, usize), RegexError> {
+    let truncated = || RegexError::TruncatedInstruction { offset };
+    let word = buf.get(offset..offset + INSTRUCTION_SIZE).map(read_word).ok_or_else(truncated)?;
+    let size = instruction_size(word, version);
+    // Almost every instruction is a single word, so there's usually nothing more to read.
+    if size == INSTRUCTION_SIZE {
+        return Ok((parse_instruction(word)?, size));
+    }
 
-fn parse_instruction(bytes: &[u8]) -> Result<Instruction, Box<dyn Error>> {
+    let bytes = buf.get(offset..offset + size).ok_or_else(truncated)?;
+    let words: Vec<u32> = bytes.chunks_exact(INSTRUCTION_SIZE).map(read_word).collect();
+    Ok((parse_multiword(&words), size))
+}
 
-    let (b1, b2, b3, b4) = (bytes[0] as u32, bytes[1] as u32, bytes[2] as u32, bytes[3] as u32);
-    let combined = b1 << 24 | b2 << 16 | b3 << 8 | b4;
+/// How many bytes the instruction starting with `word` takes up, which only depends on its opcode.
+fn instruction_size(word: u32, version: Version) -> usize {
+    if version < Version::V2 || (word & OPCODE_MASK) >> OPCODE_SHIFT != OP_EXTENDED {
+        return INSTRUCTION_SIZE;
+    }
+    match (word & EXT_OP_MASK) >> EXT_OP_SHIFT {
+        EXT_OP_WIDE_BRANCH | EXT_OP_WIDE_SPLIT => 2 * INSTRUCTION_SIZE,
+        EXT_OP_CLASS_BITMAP => 9 * INSTRUCTION_SIZE,
+        _ => INSTRUCTION_SIZE,
+    }
+}
+
+/// Decodes an instruction that's longer than one word, given all of its words.
+fn parse_multiword(words: &[u32]) -> Instruction {
+    let (word, extra) = (words[0], words[1]);
+    match (word & EXT_OP_MASK) >> EXT_OP_SHIFT {
+        EXT_OP_WIDE_SPLIT => return Instruction::Split((word & WIDE_DEST_MASK) as usize, extra as usize),
+        EXT_OP_CLASS_BITMAP => {
+            let mut bitmap = [0; 4];
+            for (part, halves) in bitmap.iter_mut().zip(words[1..].chunks_exact(2)) {
+                *part = (halves[0] as u64) << 32 | halves[1] as u64;
+            }
+            return Instruction::ClassBitmap(bitmap);
+        }
+        _ => (),
+    }
+    Instruction::Branch{
+        c_min: ((word & CHAR_MIN_MASK) >> CHAR_MIN_SHIFT) as u8,
+        c_max: ((word & CHAR_MAX_MASK) >> CHAR_MAX_SHIFT) as u8,
+        dest: extra as usize,
+        consume: false,
+        inverted: (word & WIDE_INVERTED_MASK) != 0}
+}
 
+fn parse_instruction(combined: u32) -> Result<Instruction, RegexError> {
     let opcode = (combined & OPCODE_MASK) >> OPCODE_SHIFT;
 
     match opcode {
-        0b00 => Ok(parse_branch(combined)),
-        0b01 => Ok(parse_split(combined)),
-        0b10 => Ok(parse_save(combined)),
-        _ => Err(Box::new(ParseError {
-                instruction: combined,
-                message: format!("Did not recognize opcode {:#05b}", opcode)})),
+        OP_BRANCH => Ok(parse_branch(combined)),
+        OP_SPLIT => Ok(parse_split(combined)),
+        OP_SAVE => Ok(parse_save(combined)),
+        _ => parse_extended(combined),
+    }
+}
+
+fn parse_extended(instruction: u32) -> Result<Instruction, RegexError> {
+    let ext_op = (instruction & EXT_OP_MASK) >> EXT_OP_SHIFT;
+    match ext_op {
+        EXT_OP_BACKREF => {
+            let group = (instruction & SAVE_INDEX_MASK) >> SAVE_INDEX_SHIFT;
+            Ok(Instruction::Backref(group as usize))
+        }
+        EXT_OP_BYTE => {
+            let c = (instruction & CHAR_MAX_MASK) >> CHAR_MAX_SHIFT;
+            Ok(Instruction::Byte(c as u8))
+        }
+        EXT_OP_ASSERT => match (instruction & CHAR_MAX_MASK) >> CHAR_MAX_SHIFT {
+            ASSERT_START => Ok(Instruction::Assert(Assertion::Start)),
+            ASSERT_END => Ok(Instruction::Assert(Assertion::End)),
+            ASSERT_LINE_START => Ok(Instruction::Assert(Assertion::LineStart)),
+            ASSERT_LINE_END => Ok(Instruction::Assert(Assertion::LineEnd)),
+            ASSERT_WORD_BOUNDARY => Ok(Instruction::Assert(Assertion::WordBoundary)),
+            ASSERT_NOT_WORD_BOUNDARY => Ok(Instruction::Assert(Assertion::NotWordBoundary)),
+            _ => Err(RegexError::UnknownOpcode {
+                opcode: instruction >> EXT_OP_SHIFT,
+                instruction}),
+        },
+        EXT_OP_FAIL => Ok(Instruction::Fail),
+        _ => Err(RegexError::UnknownOpcode {
+            opcode: instruction >> EXT_OP_SHIFT,
+            instruction}),
+    }
+}
+
+/// Encodes a program into the newest version of the bytecode format read by `parse_bin`.
+pub fn encode(prog: &[Instruction]) -> Result<Vec<u8>, RegexError> {
+    encode_version(prog, Version::default())
+}
+
+/// Encodes a program into a particular version of the bytecode format, for tools that only read the
+/// older versions. Programs that jump past instruction 4095 can't be encoded as version 1, and give
+/// a `DestinationOverflow` instead. Neither can programs with a `ClassBitmap`, which give an
+/// `UnsupportedInstruction`.
+pub fn encode_version(prog: &[Instruction], version: Version) -> Result<Vec<u8>, RegexError> {
+    let header_size = version.header_size();
+    let mut buf = vec![0; header_size];
+    for (pc, inst) in prog.iter().enumerate() {
+        let words = match encode_multiword(pc, inst)? {
+            Some(words) if version >= Version::V2 => words,
+            _ => vec![encode_instruction(pc, inst)?],
+        };
+        for word in words {
+            buf.extend_from_slice(&word.to_be_bytes());
+        }
+    }
+
+    if version >= Version::V2 {
+        buf[..HEADER_MAGIC.len()].copy_from_slice(HEADER_MAGIC);
+        buf[HEADER_MAGIC.len()] = version.number();
+    }
+    if version >= Version::V3 {
+        let checksum = crc32(&buf[header_size..]);
+        buf[HEADER_SIZE..header_size].copy_from_slice(&checksum.to_be_bytes());
+    }
+    Ok(buf)
+}
+
+/// Encodes the instruction in several words if it doesn't fit in one, or returns `None` if it does.
+fn encode_multiword(pc: usize, inst: &Instruction) -> Result<Option<Vec<u32>>, RegexError> {
+    let fits = |dest: usize| dest <= (DEST_MASK >> DEST_SHIFT) as usize;
+    let words = match *inst {
+        Instruction::Branch{c_min, c_max, dest, consume: false, inverted} if !fits(dest) => vec![
+            OP_EXTENDED << OPCODE_SHIFT
+                | EXT_OP_WIDE_BRANCH << EXT_OP_SHIFT
+                | flag(inverted, WIDE_INVERTED_MASK)
+                | (c_min as u32) << CHAR_MIN_SHIFT
+                | (c_max as u32) << CHAR_MAX_SHIFT,
+            encode_dest(pc, dest, u32::MAX, 0)?,
+        ],
+        Instruction::Split(dest1, dest2) if !fits(dest1) || !fits(dest2) => vec![
+            OP_EXTENDED << OPCODE_SHIFT
+                | EXT_OP_WIDE_SPLIT << EXT_OP_SHIFT
+                | encode_dest(pc, dest1, WIDE_DEST_MASK, 0)?,
+            encode_dest(pc, dest2, u32::MAX, 0)?,
+        ],
+        Instruction::ClassBitmap(bitmap) => {
+            let mut words = vec![OP_EXTENDED << OPCODE_SHIFT | EXT_OP_CLASS_BITMAP << EXT_OP_SHIFT];
+            for part in bitmap {
+                words.extend([(part >> 32) as u32, part as u32]);
+            }
+            words
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(words))
+}
+
+fn encode_instruction(pc: usize, inst: &Instruction) -> Result<u32, RegexError> {
+    let encoded = match *inst {
+        Instruction::Branch{c_min, c_max, dest, consume, inverted} => {
+            OP_BRANCH << OPCODE_SHIFT
+                | flag(inverted, INVERTED_MASK)
+                | flag(consume, CONSUME_MASK)
+                | encode_dest(pc, dest, DEST_MASK, DEST_SHIFT)?
+                | (c_min as u32) << CHAR_MIN_SHIFT
+                | (c_max as u32) << CHAR_MAX_SHIFT
+        }
+        Instruction::Split(dest1, dest2) => {
+            OP_SPLIT << OPCODE_SHIFT
+                | encode_dest(pc, dest1, DEST_MASK, DEST_SHIFT)?
+                | encode_dest(pc, dest2, DEST2_MASK, DEST2_SHIFT)?
+        }
+        Instruction::Save(index, is_match) => {
+            OP_SAVE << OPCODE_SHIFT
+                | flag(is_match, MATCH_MASK)
+                | encode_save_index(pc, index)?
+        }
+        Instruction::Backref(group) => {
+            OP_EXTENDED << OPCODE_SHIFT
+                | EXT_OP_BACKREF << EXT_OP_SHIFT
+                | encode_save_index(pc, group)?
+        }
+        Instruction::Byte(c) => {
+            OP_EXTENDED << OPCODE_SHIFT
+                | EXT_OP_BYTE << EXT_OP_SHIFT
+                | (c as u32) << CHAR_MAX_SHIFT
+        }
+        Instruction::Assert(assertion) => {
+            let kind = match assertion {
+                Assertion::Start => ASSERT_START,
+                Assertion::End => ASSERT_END,
+                Assertion::LineStart => ASSERT_LINE_START,
+                Assertion::LineEnd => ASSERT_LINE_END,
+                Assertion::WordBoundary => ASSERT_WORD_BOUNDARY,
+                Assertion::NotWordBoundary => ASSERT_NOT_WORD_BOUNDARY,
+            };
+            OP_EXTENDED << OPCODE_SHIFT
+                | EXT_OP_ASSERT << EXT_OP_SHIFT
+                | kind << CHAR_MAX_SHIFT
+        }
+        Instruction::Fail => OP_EXTENDED << OPCODE_SHIFT | EXT_OP_FAIL << EXT_OP_SHIFT,
+        // Version 1 doesn't have any way to write these.
+        Instruction::ClassBitmap(_) => return Err(RegexError::UnsupportedInstruction { pc }),
+    };
+    Ok(encoded)
+}
+
+/// Renders the program as a Graphviz graph, with a node for each instruction and an edge for each
+/// place a thread can go from it, for piping into `dot -Tpng`.
+pub fn to_dot(prog: &[Instruction]) -> String {
+    let mut dot = String::from("digraph program {\n    node [shape=box, fontname=monospace];\n");
+    for (pc, inst) in prog.iter().enumerate() {
+        let label = format!("{pc}: {inst}").replace('\\', "\\\\").replace('"', "\\\"");
+        writeln!(dot, "    {pc} [label=\"{label}\"];").unwrap();
+
+        let labels = match inst {
+            Instruction::Split(..) => ["1", "2"],
+            Instruction::Branch{consume: false, ..} => ["taken", "not taken"],
+            _ => ["", ""],
+        };
+        let [first, second] = validate::successors(pc, inst);
+        for (dest, edge_label) in [first, second].into_iter().zip(labels) {
+            match dest {
+                // There's only one way to go, so the edge doesn't need a label.
+                Some(dest) if edge_label.is_empty() || second.is_none() => {
+                    writeln!(dot, "    {pc} -> {dest};").unwrap();
+                }
+                Some(dest) => writeln!(dot, "    {pc} -> {dest} [label=\"{edge_label}\"];").unwrap(),
+                None => (),
+            }
+        }
     }
+    dot.push_str("}\n");
+    dot
+}
+
+fn flag(value: bool, mask: u32) -> u32 {
+    if value { mask } else { 0 }
+}
+
+fn encode_dest(pc: usize, dest: usize, mask: u32, shift: u32) -> Result<u32, RegexError> {
+    match u32::try_from(dest) {
+        Ok(value) if value <= mask >> shift => Ok(value << shift),
+        _ => Err(RegexError::DestinationOverflow { pc, dest }),
+    }
+}
+
+fn encode_save_index(pc: usize, index: usize) -> Result<u32, RegexError> {
+    match u32::try_from(index) {
+        Ok(value) if value <= SAVE_INDEX_MASK >> SAVE_INDEX_SHIFT => Ok(value << SAVE_INDEX_SHIFT),
+        _ => Err(RegexError::SaveIndexOverflow { pc, index }),
+    }
+}
+
+/// Makes sure that every jump in the program lands on an instruction.
+fn check_destinations(prog: &[Instruction]) -> Result<(), RegexError> {
+    for (pc, inst) in prog.iter().enumerate() {
+        let dests = match *inst {
+            Instruction::Branch{dest, consume: false, ..} => [Some(dest), None],
+            Instruction::Split(dest1, dest2) => [Some(dest1), Some(dest2)],
+            _ => [None, None],
+        };
+        if let Some(dest) = dests.into_iter().flatten().find(|dest| *dest >= prog.len()) {
+            return Err(RegexError::InvalidDestination { pc, dest });
+        }
+    }
+    Ok(())
 }
 
 
@@ -89,8 +482,8 @@ fn parse_branch(instruction: u32) -> Instruction {
         c_min: char_min as u8,
         c_max: char_max as u8,
         dest: dest as usize,
-        consume: consume,
-        inverted: inverted}
+        consume,
+        inverted}
 }
 
 fn parse_save(instruction: u32) -> Instruction {
@@ -98,3 +491,172 @@ fn parse_save(instruction: u32) -> Instruction {
     let index = (instruction & SAVE_INDEX_MASK) >> SAVE_INDEX_SHIFT;
     Instruction::Save(index as usize, is_match)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_partial_word_is_truncated() {
+        // A `save 0` and then two bytes of another instruction.
+        let result = parse_bytes(&[0x80, 0x00, 0x00, 0x00, 0x80, 0x00]);
+        assert!(matches!(result, Err(RegexError::TruncatedInstruction { offset: 4 })), "{result:?}");
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn missing_files_are_io_errors() {
+        let result = parse_bin("/this/file/does/not/exist.bin");
+        assert!(matches!(result, Err(RegexError::Io(_))), "{result:?}");
+    }
+
+    #[test]
+    fn unused_extended_opcodes_are_unknown() {
+        let result = parse_bytes(&[0xFE, 0x00, 0x00, 0x00]);
+        assert!(
+            matches!(result, Err(RegexError::UnknownOpcode { opcode: 0xFE, instruction: 0xFE00_0000 })),
+            "{result:?}");
+    }
+
+    #[test]
+    fn jumps_past_the_end_are_invalid() {
+        let buf = encode(&[Instruction::Split(1, 5), Instruction::Save(1, true)]).unwrap();
+        let result = parse_bytes(&buf);
+        assert!(matches!(result, Err(RegexError::InvalidDestination { pc: 0, dest: 5 })), "{result:?}");
+    }
+
+    #[test]
+    fn encoded_programs_parse_back_to_themselves() {
+        for pattern in ["abc", "a+b|c*", "^(\\w+)\\s\\1$", "[^a-c]x?", "\\bfoo\\B", "(a|b)*?c"] {
+            let prog = crate::regex::compile(pattern).unwrap();
+            for version in [Version::V2, Version::V3] {
+                let parsed = parse_bytes(&encode_version(&prog, version).unwrap()).unwrap();
+                assert_eq!(parsed, prog, "{pattern:?} as {version:?}");
+            }
+        }
+        let prog = [Instruction::Assert(Assertion::Start), Instruction::Byte(b'x'), Instruction::Save(1, true)];
+        assert_eq!(parse_bytes(&encode_version(&prog, Version::V1).unwrap()).unwrap(), prog);
+    }
+
+    #[test]
+    fn destinations_that_do_not_fit_overflow() {
+        let prog = [Instruction::Split(1, 5000), Instruction::Save(1, true)];
+        let result = encode_version(&prog, Version::V1);
+        assert!(matches!(result, Err(RegexError::DestinationOverflow { pc: 0, dest: 5000 })), "{result:?}");
+
+        let prog = [Instruction::Split(1 << 24, 0)];
+        let result = encode(&prog);
+        assert!(matches!(result, Err(RegexError::DestinationOverflow { pc: 0, dest: 0x100_0000 })), "{result:?}");
+    }
+
+    #[test]
+    fn programs_need_an_instruction() {
+        assert!(matches!(parse_bytes(&[]), Err(RegexError::EmptyProgram)));
+        assert!(matches!(parse_bytes(&encode(&[]).unwrap()), Err(RegexError::EmptyProgram)));
+    }
+
+    #[test]
+    fn byte_instructions_round_trip_and_match_one_byte() {
+        let prog = vec![Instruction::Save(0, false), Instruction::Byte(b'x'), Instruction::Save(1, true)];
+        for version in [Version::V1, Version::V2, Version::V3] {
+            assert_eq!(parse_bytes(&encode_version(&prog, version).unwrap()).unwrap(), prog);
+        }
+        for b in 0..=u8::MAX {
+            let expected = (b == b'x').then_some((0, 1));
+            assert_eq!(crate::interpreter::search_bytes(&prog, &[b]), expected, "{b:#04x}");
+        }
+    }
+
+    #[test]
+    fn dot_has_an_edge_for_every_target() {
+        let prog = [
+            Instruction::Split(1, 3),
+            Instruction::Byte(b'a'),
+            Instruction::Branch{c_min: 0, c_max: u8::MAX, dest: 5, consume: false, inverted: false},
+            Instruction::Branch{c_min: b'0', c_max: b'9', dest: 5, consume: false, inverted: false},
+            Instruction::Fail,
+            Instruction::Save(1, true),
+        ];
+        let dot = to_dot(&prog);
+        let edges: Vec<&str> = dot.lines().filter(|line| line.contains("->")).map(str::trim).collect();
+        assert_eq!(edges, [
+            "0 -> 1 [label=\"1\"];",
+            "0 -> 3 [label=\"2\"];",
+            "1 -> 2;",
+            "2 -> 5;",
+            "3 -> 5 [label=\"taken\"];",
+            "3 -> 4 [label=\"not taken\"];",
+        ]);
+        let nodes = dot.lines().filter(|line| line.contains("[label=\"") && !line.contains("->"));
+        assert_eq!(nodes.count(), prog.len());
+    }
+
+    #[test]
+    fn far_destinations_take_two_words() {
+        let mut prog = vec![
+            Instruction::Save(0, false),
+            Instruction::Split(2, 4999),
+            Instruction::Branch{c_min: b'z', c_max: b'z', dest: 5000, consume: false, inverted: false},
+        ];
+        prog.resize(4999, Instruction::Fail);
+        prog.extend([Instruction::Byte(b'y'), Instruction::Byte(b'z'), Instruction::Save(1, true)]);
+
+        let parsed = parse_bytes(&encode(&prog).unwrap()).unwrap();
+        assert_eq!(parsed, prog);
+        assert_eq!(crate::interpreter::search(&parsed, "z"), Some((0, 1)));
+        assert_eq!(crate::interpreter::search(&parsed, "yz"), Some((0, 2)));
+        assert_eq!(crate::interpreter::search(&parsed, "x"), None);
+
+        let result = encode_version(&prog, Version::V1);
+        assert!(matches!(result, Err(RegexError::DestinationOverflow { pc: 1, dest: 4999 })), "{result:?}");
+    }
+
+    #[test]
+    fn one_and_several_word_instructions_mix() {
+        let bitmap = crate::regex::class_bitmap(&[(b'a', b'c'), (b'x', b'z')]);
+        let prog = [
+            Instruction::Save(0, false),
+            Instruction::ClassBitmap(bitmap),
+            Instruction::Byte(b'!'),
+            Instruction::Save(1, true),
+        ];
+        let buf = encode_version(&prog, Version::V2).unwrap();
+        assert_eq!(buf.len(), HEADER_SIZE + 12 * INSTRUCTION_SIZE);
+        assert_eq!(parse_bytes(&buf).unwrap(), prog);
+    }
+
+    #[test]
+    fn a_cut_off_final_instruction_is_truncated() {
+        let bitmap = crate::regex::class_bitmap(&[(b'0', b'9')]);
+        let prog = [Instruction::Save(0, false), Instruction::Byte(b'#'), Instruction::ClassBitmap(bitmap)];
+        let buf = encode_version(&prog, Version::V2).unwrap();
+        let offset = HEADER_SIZE + 2 * INSTRUCTION_SIZE;
+        for len in [buf.len() - INSTRUCTION_SIZE, buf.len() - 1, offset + INSTRUCTION_SIZE] {
+            let result = parse_bytes(&buf[..len]);
+            assert!(
+                matches!(result, Err(RegexError::TruncatedInstruction { offset: o }) if o == offset),
+                "{len}: {result:?}");
+        }
+    }
+
+    #[test]
+    fn checksums_catch_flipped_bytes() {
+        let prog = crate::regex::compile("a[b-d]+e").unwrap();
+        let buf = encode(&prog).unwrap();
+        assert_eq!(parse_bytes(&buf).unwrap(), prog);
+        // Encoding the parsed program again gives back exactly the same bytes, checksum and all.
+        assert_eq!(encode(&parse_bytes(&buf).unwrap()).unwrap(), buf);
+
+        let mut corrupted = buf.clone();
+        // The `e`, which still decodes to a valid program when it's changed.
+        let last_byte = corrupted.len() - INSTRUCTION_SIZE - 1;
+        corrupted[last_byte] ^= 0x01;
+        let result = parse_bytes(&corrupted);
+        assert!(matches!(result, Err(RegexError::ChecksumMismatch { .. })), "{result:?}");
+
+        let options = ParseOptions { skip_checksum: true, ..ParseOptions::default() };
+        let parsed = parse_bytes_with(&corrupted, &options).unwrap();
+        assert_eq!(parsed.len(), prog.len());
+        assert_ne!(parsed, prog);
+    }
+}
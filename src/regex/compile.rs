@@ -0,0 +1,600 @@
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::error::RegexError;
+use crate::interpreter::{Fold, SearchOptions};
+use crate::regex::{class_bitmap, Assertion, Instruction};
+
+/// The byte ranges matched by `\d`.
+const DIGIT: &[(u8, u8)] = &[(b'0', b'9')];
+/// The byte ranges matched by `\w`.
+const WORD: &[(u8, u8)] = &[(b'0', b'9'), (b'A', b'Z'), (b'_', b'_'), (b'a', b'z')];
+/// The byte ranges matched by `\s`.
+const SPACE: &[(u8, u8)] = &[(b'\t', b'\n'), (b'\x0C', b'\r'), (b' ', b' ')];
+
+/// The parsed form of a pattern.
+#[derive(Debug)]
+enum Node {
+    Empty,
+    /// Matches any single byte inside one of the (sorted and non-overlapping) ranges.
+    Class(Vec<(u8, u8)>),
+    Concat(Vec<Node>),
+    Alternate(Vec<Node>),
+    Repeat { node: Box<Node>, min: usize, max: Option<usize>, greedy: bool },
+    /// A capturing group, which saves its span in slots `2 * index` and `2 * index + 1`.
+    Group(usize, Box<Node>),
+    Backref(usize),
+    Assert(Assertion),
+}
+
+/// Compiles a regular expression into a program for the interpreter.
+///
+/// The program searches for the pattern anywhere in the input, saving the start and end of the
+/// match in slots 0 and 1. Quantifiers are split into a preferred branch and a fallback: greedy
+/// ones emit `Split(body, exit)` and lazy ones emit `Split(exit, body)`.
+pub fn compile(pattern: &str) -> Result<Vec<Instruction>, RegexError> {
+    compile_with(pattern, &SearchOptions::default())
+}
+
+/// Like `compile`, but for a program that will be searched with `options`.
+///
+/// This only matters for case-insensitive searches with `Fold::Unicode`. The interpreter only
+/// looks at one byte at a time, so it can't fold characters that are more than one byte long.
+/// Instead, each non-ASCII literal is expanded here into an alternation of its upper and lower case
+/// forms. ASCII letters are still folded when the program runs.
+pub fn compile_with(pattern: &str, options: &SearchOptions) -> Result<Vec<Instruction>, RegexError> {
+    let unicode_fold = options.case_insensitive && options.fold == Fold::Unicode;
+    let mut parser = Parser { pattern, pos: 0, groups: 0, unicode_fold };
+    let node = parser.parse_alternate()?;
+    if parser.pos < pattern.len() {
+        // The only way to stop early is on a `)` that doesn't close anything.
+        return Err(parser.error("unmatched ')'"));
+    }
+
+    let mut compiler = Compiler { prog: Vec::new() };
+    compiler.emit_prefix();
+    compiler.prog.push(Instruction::Save(0, false));
+    compiler.emit(&node);
+    compiler.prog.push(Instruction::Save(1, true));
+    Ok(compiler.prog)
+}
+
+struct Parser<'a> {
+    pattern: &'a str,
+    pos: usize,
+    /// The number of capturing groups opened so far.
+    groups: usize,
+    /// Expand non-ASCII literals to match their other cases too.
+    unicode_fold: bool,
+}
+
+impl <'a> Parser<'a> {
+    fn error(&self, message: &str) -> RegexError {
+        RegexError::InvalidPattern { pos: self.pos, message: message.to_string() }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.pattern[self.pos..].chars().next()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn parse_alternate(&mut self) -> Result<Node, RegexError> {
+        let mut alternatives = vec![self.parse_concat()?];
+        while self.peek() == Some('|') {
+            self.next();
+            alternatives.push(self.parse_concat()?);
+        }
+
+        if alternatives.len() == 1 {
+            Ok(alternatives.pop().unwrap())
+        } else {
+            Ok(Node::Alternate(alternatives))
+        }
+    }
+
+    fn parse_concat(&mut self) -> Result<Node, RegexError> {
+        let mut nodes = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            nodes.push(self.parse_repeat()?);
+        }
+
+        match nodes.len() {
+            0 => Ok(Node::Empty),
+            1 => Ok(nodes.pop().unwrap()),
+            _ => Ok(Node::Concat(nodes)),
+        }
+    }
+
+    fn parse_repeat(&mut self) -> Result<Node, RegexError> {
+        let mut node = self.parse_atom()?;
+        while let Some(c) = self.peek() {
+            let (min, max) = match c {
+                '*' => (0, None),
+                '+' => (1, None),
+                '?' => (0, Some(1)),
+                _ => break,
+            };
+            self.next();
+
+            // A trailing `?` makes the quantifier lazy.
+            let greedy = self.peek() != Some('?');
+            if !greedy {
+                self.next();
+            }
+            node = Node::Repeat { node: Box::new(node), min, max, greedy };
+        }
+        Ok(node)
+    }
+
+    fn parse_atom(&mut self) -> Result<Node, RegexError> {
+        let start = self.pos;
+        match self.next() {
+            Some('(') => {
+                self.groups += 1;
+                let index = self.groups;
+                let node = self.parse_alternate()?;
+                if self.next() != Some(')') {
+                    self.pos = start;
+                    return Err(self.error("unclosed '('"));
+                }
+                Ok(Node::Group(index, Box::new(node)))
+            }
+            Some('*' | '+' | '?') => {
+                self.pos = start;
+                Err(self.error("quantifier does not follow anything"))
+            }
+            Some('.') => Ok(Node::Class(negate(&[(b'\n', b'\n')]))),
+            Some('[') => self.parse_class(start),
+            Some('^') => Ok(Node::Assert(Assertion::Start)),
+            Some('$') => Ok(Node::Assert(Assertion::End)),
+            Some('\\') => self.parse_escape(),
+            Some(c) if self.unicode_fold && !c.is_ascii() => Ok(folded_literal(c)),
+            Some(c) => Ok(literal(c)),
+            None => Err(self.error("unexpected end of pattern")),
+        }
+    }
+
+    /// Parses the inside of a `[...]` class, after the opening `[` at `start`.
+    fn parse_class(&mut self, start: usize) -> Result<Node, RegexError> {
+        let negated = self.peek() == Some('^');
+        if negated {
+            self.next();
+        }
+
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            let min = match self.next() {
+                // A `]` right at the start is part of the class rather than the end of it.
+                Some(']') if !first => break,
+                Some('\\') => match self.parse_class_escape()? {
+                    ClassItem::Byte(b) => b,
+                    ClassItem::Ranges(set) => {
+                        ranges.extend_from_slice(set);
+                        first = false;
+                        continue;
+                    }
+                },
+                Some(c) => self.class_byte(c)?,
+                None => {
+                    self.pos = start;
+                    return Err(self.error("unclosed '['"));
+                }
+            };
+            first = false;
+
+            // A `-` is a range unless it's the last thing in the class.
+            let rest = &self.pattern[self.pos..];
+            if !rest.starts_with('-') || rest.starts_with("-]") {
+                ranges.push((min, min));
+                continue;
+            }
+            let range_start = self.pos - 1;
+            self.next();
+            let max = match self.next() {
+                Some('\\') => match self.parse_class_escape()? {
+                    ClassItem::Byte(b) => b,
+                    ClassItem::Ranges(_) => {
+                        self.pos = range_start;
+                        return Err(self.error("class escapes can't be the end of a range"));
+                    }
+                },
+                Some(c) => self.class_byte(c)?,
+                None => {
+                    self.pos = start;
+                    return Err(self.error("unclosed '['"));
+                }
+            };
+            if max < min {
+                self.pos = range_start;
+                return Err(self.error("range is out of order"));
+            }
+            ranges.push((min, max));
+        }
+
+        let ranges = normalize(ranges);
+        if negated {
+            Ok(Node::Class(negate(&ranges)))
+        } else {
+            Ok(Node::Class(ranges))
+        }
+    }
+
+    /// Parses an escape inside a class, after the `\`.
+    fn parse_class_escape(&mut self) -> Result<ClassItem, RegexError> {
+        let start = self.pos - 1;
+        match self.next() {
+            Some('d') => Ok(ClassItem::Ranges(DIGIT)),
+            Some('w') => Ok(ClassItem::Ranges(WORD)),
+            Some('s') => Ok(ClassItem::Ranges(SPACE)),
+            Some(c) if let Some(b) = self.parse_byte_escape(c)? => Ok(ClassItem::Byte(b)),
+            Some(_) => {
+                self.pos = start;
+                Err(self.error("unsupported escape sequence"))
+            }
+            None => Err(self.error("pattern ends with a '\\'")),
+        }
+    }
+
+    /// Parses the escapes that stand for a single byte, after the `\\` and the character `c`. Returns
+    /// `None` if `c` doesn't start one of them.
+    fn parse_byte_escape(&mut self, c: char) -> Result<Option<u8>, RegexError> {
+        let b = match c {
+            't' => b'\t',
+            'n' => b'\n',
+            'r' => b'\r',
+            '0' => b'\0',
+            'x' => {
+                let digits = self.pattern[self.pos..].get(..2)
+                    .filter(|digits| digits.bytes().all(|d| d.is_ascii_hexdigit()));
+                match digits.and_then(|digits| u8::from_str_radix(digits, 16).ok()) {
+                    Some(b) => {
+                        self.pos += 2;
+                        b
+                    }
+                    None => {
+                        self.pos -= 2;
+                        return Err(self.error("expected two hex digits after '\\x'"));
+                    }
+                }
+            }
+            // Metacharacters stand for themselves once they're escaped.
+            '.' | '*' | '+' | '?' | '(' | ')' | '[' | ']' | '{' | '}' | '|' | '^' | '$' | '-' | '\\' => c as u8,
+            _ => return Ok(None),
+        };
+        Ok(Some(b))
+    }
+
+    /// Checks that a character in a class fits in one byte, since classes match single bytes.
+    fn class_byte(&mut self, c: char) -> Result<u8, RegexError> {
+        if !c.is_ascii() {
+            self.pos -= c.len_utf8();
+            return Err(self.error("classes can only contain ASCII characters"));
+        }
+        Ok(c as u8)
+    }
+
+    fn parse_escape(&mut self) -> Result<Node, RegexError> {
+        let start = self.pos - 1;
+        let ranges = match self.next() {
+            Some('d') => DIGIT.to_vec(),
+            Some('D') => negate(DIGIT),
+            Some('w') => WORD.to_vec(),
+            Some('W') => negate(WORD),
+            Some('s') => SPACE.to_vec(),
+            Some('S') => negate(SPACE),
+            Some('b') => return Ok(Node::Assert(Assertion::WordBoundary)),
+            Some('B') => return Ok(Node::Assert(Assertion::NotWordBoundary)),
+            Some(c) if let Some(b) = self.parse_byte_escape(c)? => vec![(b, b)],
+            Some(c @ '1'..='9') => {
+                let group = c as usize - '0' as usize;
+                if group > self.groups {
+                    self.pos = start;
+                    return Err(self.error("backreference to a group that doesn't exist"));
+                }
+                return Ok(Node::Backref(group));
+            }
+            Some(_) => {
+                self.pos = start;
+                return Err(self.error("unsupported escape sequence"));
+            }
+            None => return Err(self.error("pattern ends with a '\\'")),
+        };
+        Ok(Node::Class(ranges))
+    }
+}
+
+/// Builds a node matching `c` or its upper or lower case form.
+///
+/// This only uses the simple mappings from one character to another, so characters like `ß` whose
+/// upper case is more than one character just match themselves.
+fn folded_literal(c: char) -> Node {
+    let single = |mapped: String| {
+        let mut chars = mapped.chars();
+        match (chars.next(), chars.next()) {
+            (Some(other), None) => Some(other),
+            _ => None,
+        }
+    };
+    let mut cases = vec![c];
+    let others = [single(c.to_lowercase().to_string()), single(c.to_uppercase().to_string())];
+    for other in others.into_iter().flatten() {
+        if !cases.contains(&other) {
+            cases.push(other);
+        }
+    }
+
+    if cases.len() == 1 {
+        literal(c)
+    } else {
+        Node::Alternate(cases.into_iter().map(literal).collect())
+    }
+}
+
+/// Something that can appear after a `\` inside a class.
+enum ClassItem {
+    Byte(u8),
+    Ranges(&'static [(u8, u8)]),
+}
+
+/// Sorts the ranges and merges the ones that overlap or touch.
+fn normalize(mut ranges: Vec<(u8, u8)>) -> Vec<(u8, u8)> {
+    ranges.sort_unstable();
+    let mut merged: Vec<(u8, u8)> = Vec::new();
+    for (min, max) in ranges {
+        match merged.last_mut() {
+            Some(last) if min as u16 <= last.1 as u16 + 1 => last.1 = last.1.max(max),
+            _ => merged.push((min, max)),
+        }
+    }
+    merged
+}
+
+/// Builds a node matching the UTF-8 encoding of `c`.
+fn literal(c: char) -> Node {
+    let mut buf = [0; 4];
+    let mut bytes: Vec<Node> = c.encode_utf8(&mut buf).bytes()
+        .map(|b| Node::Class(vec![(b, b)]))
+        .collect();
+    if bytes.len() == 1 {
+        bytes.pop().unwrap()
+    } else {
+        Node::Concat(bytes)
+    }
+}
+
+/// Returns the ranges of every byte that is not in `ranges`, which must be sorted.
+fn negate(ranges: &[(u8, u8)]) -> Vec<(u8, u8)> {
+    let mut negated = Vec::new();
+    let mut next: u16 = 0;
+    for &(min, max) in ranges {
+        if next < min as u16 {
+            negated.push((next as u8, min - 1));
+        }
+        next = max as u16 + 1;
+    }
+    if next <= u8::MAX as u16 {
+        negated.push((next as u8, u8::MAX));
+    }
+    negated
+}
+
+struct Compiler {
+    prog: Vec<Instruction>,
+}
+
+impl Compiler {
+    fn jump(dest: usize) -> Instruction {
+        Instruction::Branch{c_min: 0, c_max: u8::MAX, dest, consume: false, inverted: false}
+    }
+
+    fn consume(c_min: u8, c_max: u8, inverted: bool) -> Instruction {
+        Instruction::Branch{c_min, c_max, dest: 0, consume: true, inverted}
+    }
+
+    /// Emits a lazy `.*` over every byte so that the match can start anywhere in the input.
+    fn emit_prefix(&mut self) {
+        let start = self.prog.len();
+        self.prog.push(Instruction::Split(start + 3, start + 1));
+        self.prog.push(Compiler::consume(0, u8::MAX, false));
+        self.prog.push(Compiler::jump(start));
+    }
+
+    fn emit(&mut self, node: &Node) {
+        match node {
+            Node::Empty => (),
+            Node::Class(ranges) => self.emit_class(ranges),
+            Node::Concat(nodes) => {
+                for node in nodes {
+                    self.emit(node);
+                }
+            }
+            Node::Alternate(nodes) => self.emit_alternate(nodes),
+            Node::Repeat { node, min, max, greedy } => self.emit_repeat(node, *min, *max, *greedy),
+            Node::Group(index, node) => {
+                self.prog.push(Instruction::Save(index * 2, false));
+                self.emit(node);
+                self.prog.push(Instruction::Save(index * 2 + 1, false));
+            }
+            Node::Backref(group) => self.prog.push(Instruction::Backref(*group)),
+            Node::Assert(assertion) => self.prog.push(Instruction::Assert(*assertion)),
+        }
+    }
+
+    fn emit_class(&mut self, ranges: &[(u8, u8)]) {
+        let negated = negate(ranges);
+        match (ranges, negated.as_slice()) {
+            // Nothing can match an empty class.
+            ([], _) => self.prog.push(Instruction::Fail),
+            ([(min, max)], _) if min == max => self.prog.push(Instruction::Byte(*min)),
+            ([(min, max)], _) => self.prog.push(Compiler::consume(*min, *max, false)),
+            (_, [(min, max)]) => self.prog.push(Compiler::consume(*min, *max, true)),
+            // Anything else would take an alternation of ranges, which is a lot more instructions
+            // and threads than checking a bit.
+            _ => self.prog.push(Instruction::ClassBitmap(class_bitmap(ranges))),
+        }
+    }
+
+    /// Emits a chain of splits, each of which tries one alternative before moving on to the rest.
+    fn emit_alternate(&mut self, nodes: &[Node]) {
+        let mut jumps = Vec::new();
+        for (i, node) in nodes.iter().enumerate() {
+            if i == nodes.len() - 1 {
+                self.emit(node);
+                break;
+            }
+
+            let split = self.prog.len();
+            self.prog.push(Instruction::Split(split + 1, 0));
+            self.emit(node);
+            jumps.push(self.prog.len());
+            self.prog.push(Compiler::jump(0));
+            self.prog[split] = Instruction::Split(split + 1, self.prog.len());
+        }
+
+        let end = self.prog.len();
+        for jump in jumps {
+            self.prog[jump] = Compiler::jump(end);
+        }
+    }
+
+    /// Emits a quantifier around `node`.
+    ///
+    /// Greediness is encoded in the order of the split's branches: a greedy quantifier emits
+    /// `Split(body, exit)` so that the thread going around again comes first, and a lazy one emits
+    /// `Split(exit, body)`. The interpreter keeps every thread either way, so this doesn't change
+    /// what `search` finds, but it keeps the quantifier's greediness recoverable from the program
+    /// (`asm::disassemble` uses it to label splits).
+    fn emit_repeat(&mut self, node: &Node, min: usize, max: Option<usize>, greedy: bool) {
+        let split = |body: usize, exit: usize| if greedy {
+            Instruction::Split(body, exit)
+        } else {
+            Instruction::Split(exit, body)
+        };
+
+        match (min, max) {
+            // x*
+            (0, None) => {
+                let start = self.prog.len();
+                self.prog.push(Instruction::Split(0, 0));
+                self.emit(node);
+                self.prog.push(Compiler::jump(start));
+                self.prog[start] = split(start + 1, self.prog.len());
+            }
+            // x+
+            (1, None) => {
+                let start = self.prog.len();
+                self.emit(node);
+                let exit = self.prog.len() + 1;
+                self.prog.push(split(start, exit));
+            }
+            // x?
+            _ => {
+                let start = self.prog.len();
+                self.prog.push(Instruction::Split(0, 0));
+                self.emit(node);
+                self.prog[start] = split(start + 1, self.prog.len());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_pos(pattern: &str) -> usize {
+        match compile(pattern) {
+            Err(RegexError::InvalidPattern { pos, .. }) => pos,
+            result => panic!("{pattern:?} gave {result:?}"),
+        }
+    }
+
+    #[test]
+    fn bad_escapes_are_reported_at_the_backslash() {
+        assert_eq!(error_pos("\\€"), 0);
+        assert_eq!(error_pos("ab\\€"), 2);
+        assert_eq!(error_pos("[\\€]"), 1);
+        assert_eq!(error_pos("(a)\\2"), 3);
+        assert_eq!(error_pos("a\\q"), 1);
+    }
+
+    fn find(pattern: &str, input: &str) -> Option<(usize, usize)> {
+        crate::interpreter::search(&compile(pattern).unwrap(), input)
+    }
+
+    #[test]
+    fn shorthand_classes_match_their_bytes() {
+        assert_eq!(find("\\d+", "abc 0123 45"), Some((4, 8)));
+        assert_eq!(find("\\d+", "no digits"), None);
+        assert_eq!(find("\\w+", "  foo_Bar9!"), Some((2, 10)));
+        assert_eq!(find("\\w", "!?.,-"), None);
+        assert_eq!(find("\\s+", "a \t\nb"), Some((1, 4)));
+    }
+
+    #[test]
+    fn upper_case_shorthands_are_negated() {
+        assert_eq!(find("\\D+", "12ab34"), Some((2, 4)));
+        assert_eq!(find("\\D", "0123456789"), None);
+        assert_eq!(find("\\W+", "foo, bar"), Some((3, 5)));
+        assert_eq!(find("\\W", "foo_bar"), None);
+        assert_eq!(find("\\S+", "  xy "), Some((2, 4)));
+        assert_eq!(find("\\S", " \t\r\n"), None);
+    }
+
+    #[test]
+    fn backreferences_match_the_same_text_again() {
+        assert_eq!(find("(\\w+)\\s+\\1", "hello hello"), Some((0, 11)));
+        assert_eq!(find("(\\w+)\\s+\\1", "hello world"), None);
+        assert_eq!(find("(a|b)\\1", "abba"), Some((1, 3)));
+    }
+
+    #[test]
+    fn classes_match_their_ranges() {
+        assert_eq!(find("[a-c]+", "xxbcaz"), Some((2, 5)));
+        assert_eq!(find("[^a-c]+", "abxyzc"), Some((2, 5)));
+        assert_eq!(find("[^a-c]", "abcabc"), None);
+        assert_eq!(find("[a-cx-z0-9]+", "--a0zq"), Some((2, 5)));
+        assert_eq!(find("[a-cx-z]", "mnop"), None);
+    }
+
+    #[test]
+    fn escaped_brackets_and_dashes_are_literal_in_classes() {
+        assert_eq!(find("[\\]]+", "a]]b"), Some((1, 3)));
+        assert_eq!(find("[a\\-z]+", "bb-az"), Some((2, 5)));
+        assert_eq!(find("[a\\-z]", "m"), None);
+    }
+
+    #[test]
+    fn byte_escapes_match_the_same_as_the_byte() {
+        assert_eq!(compile("\\x41").unwrap(), compile("A").unwrap());
+        assert_eq!(compile("\\t").unwrap(), compile("\t").unwrap());
+        assert_eq!(find("a\\tb", "xa\tb"), Some((1, 4)));
+
+        let assembled = crate::regex::asm::assemble("char '\\x41'\nchar '\\t'\nchar 0x41").unwrap();
+        assert_eq!(assembled, [Instruction::Byte(b'A'), Instruction::Byte(b'\t'), Instruction::Byte(b'A')]);
+    }
+
+    #[test]
+    fn unicode_folding_matches_other_cases_of_non_ascii_letters() {
+        let search = |fold: Fold, input: &str| {
+            let options = SearchOptions { case_insensitive: true, fold, ..SearchOptions::default() };
+            let prog = compile_with("Ä", &options).unwrap();
+            crate::interpreter::search_with(&prog, input, &options).unwrap()
+        };
+        assert_eq!(search(Fold::Unicode, "xä"), Some((1, 3)));
+        assert_eq!(search(Fold::Unicode, "xÄ"), Some((1, 3)));
+        assert_eq!(search(Fold::Ascii, "xä"), None);
+        assert_eq!(search(Fold::Ascii, "xÄ"), Some((1, 3)));
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::HashSet;
+
+use crate::interpreter;
+use crate::regex::{bitmap_contains, Assertion, Instruction};
+
+/// Whether the interpreter finds a match for the program anywhere in `input`.
+pub fn nfa_matches(prog: &[Instruction], input: &str) -> bool {
+    interpreter::search(prog, input).is_some()
+}
+
+/// Whether the program can reach a match on `input`, worked out by following every path through it
+/// one at a time. This should always agree with `nfa_matches`.
+///
+/// None of the interpreter's tricks are used here: there are no thread groups, no merging and no
+/// lock step over the input, just a depth first search over every state a thread could be in. The
+/// only thing that stops loops which don't consume anything from going around forever is skipping
+/// states that have already been seen. That makes it exponential in the worst case, so it's only
+/// good for small programs and inputs.
+///
+/// Some quirks of the interpreter are copied on purpose so that the two can be compared: the search
+/// only starts at the beginning of the input (the search prefix is what lets compiled programs
+/// match later on), and a backreference to a group that hasn't been saved matches the empty string.
+/// There's no byte at the end of the input, so no range contains it, but a jump is still taken.
+pub fn reference_matches(prog: &[Instruction], input: &str) -> bool {
+    let input = input.as_bytes();
+    let slots = prog.iter()
+        .map(|inst| match *inst {
+            Instruction::Save(index, _) => index + 1,
+            Instruction::Backref(group) => group * 2 + 2,
+            _ => 0,
+        })
+        .fold(2, usize::max);
+
+    let mut seen = HashSet::new();
+    let mut to_visit = vec![(0, 0, vec![0; slots])];
+    while let Some((pc, pos, saves)) = to_visit.pop() {
+        if !seen.insert((pc, pos, saves.clone())) {
+            continue;
+        }
+        let Some(&inst) = prog.get(pc) else {
+            continue;
+        };
+        let next = input.get(pos).copied();
+        match inst {
+            Instruction::Save(_, true) => return true,
+            Instruction::Save(index, false) => {
+                let mut saves = saves;
+                saves[index] = pos;
+                to_visit.push((pc + 1, pos, saves));
+            }
+            Instruction::Branch{c_min, c_max, dest, consume, inverted} => {
+                let in_range = match next {
+                    Some(c) => c_min <= c && c <= c_max,
+                    None => !consume && c_min == 0 && c_max == u8::MAX,
+                };
+                match (consume, in_range != inverted) {
+                    (true, true) if next.is_some() => to_visit.push((pc + 1, pos + 1, saves)),
+                    (true, _) => (),
+                    (false, true) => to_visit.push((dest, pos, saves)),
+                    (false, false) => to_visit.push((pc + 1, pos, saves)),
+                }
+            }
+            Instruction::Byte(c) => {
+                if next == Some(c) {
+                    to_visit.push((pc + 1, pos + 1, saves));
+                }
+            }
+            Instruction::ClassBitmap(bitmap) => {
+                if next.is_some_and(|c| bitmap_contains(&bitmap, c)) {
+                    to_visit.push((pc + 1, pos + 1, saves));
+                }
+            }
+            Instruction::Split(dest1, dest2) => {
+                to_visit.push((dest2, pos, saves.clone()));
+                to_visit.push((dest1, pos, saves));
+            }
+            Instruction::Backref(group) => {
+                let (start, end) = (saves[group * 2], saves[group * 2 + 1]);
+                let captured = &input[start..end.max(start)];
+                if input[pos..].starts_with(captured) {
+                    to_visit.push((pc + 1, pos + captured.len(), saves));
+                }
+            }
+            Instruction::Assert(assertion) => {
+                let holds = match assertion {
+                    Assertion::Start => pos == 0,
+                    Assertion::End => next.is_none(),
+                    Assertion::LineStart => pos == 0 || input[pos - 1] == b'\n',
+                    Assertion::LineEnd => matches!(next, None | Some(b'\n')),
+                    Assertion::WordBoundary | Assertion::NotWordBoundary => {
+                        let is_word = |c: Option<u8>| c.is_some_and(|c| c.is_ascii_alphanumeric() || c == b'_');
+                        let previous = pos.checked_sub(1).map(|index| input[index]);
+                        (is_word(previous) != is_word(next)) == (assertion == Assertion::WordBoundary)
+                    }
+                };
+                if holds {
+                    to_visit.push((pc + 1, pos, saves));
+                }
+            }
+            Instruction::Fail => (),
+        }
+    }
+    false
+}
+
+#[cfg(all(test, feature = "testutil"))]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::regex::compile;
+
+    /// Patterns over a small alphabet, so that random inputs have a fair chance of matching.
+    fn pattern() -> impl Strategy<Value = String> {
+        let atom = prop_oneof![
+            Just("a".to_string()),
+            Just("b".to_string()),
+            Just(".".to_string()),
+            Just("[ab]".to_string()),
+            Just("[^a]".to_string()),
+            Just("^".to_string()),
+            Just("$".to_string()),
+            Just("\\b".to_string()),
+        ];
+        atom.prop_recursive(4, 16, 3, |inner| prop_oneof![
+            prop::collection::vec(inner.clone(), 1..4).prop_map(|parts| parts.concat()),
+            (inner.clone(), inner.clone()).prop_map(|(left, right)| format!("{left}|{right}")),
+            inner.clone().prop_map(|node| format!("({node})")),
+            (inner.clone(), prop::sample::select(vec!["*", "+", "?", "*?", "+?", "??"]))
+                .prop_map(|(node, quantifier)| format!("({node}){quantifier}")),
+            // A group and a backreference to it.
+            inner.prop_map(|node| format!("({node})\\1")),
+        ])
+    }
+
+    #[test]
+    fn no_range_contains_the_end_of_the_input() {
+        let branch = |c_min, c_max, inverted| {
+            Instruction::Branch { c_min, c_max, dest: 3, consume: false, inverted }
+        };
+        // Each program matches if its branch is taken, and fails if it falls through.
+        let cases = [
+            (branch(0, 0, false), false),
+            (branch(0, 0, true), true),
+            (branch(b'a', b'z', false), false),
+            (branch(b'a', b'z', true), true),
+            (branch(0, u8::MAX, false), true),
+            (branch(0, u8::MAX, true), false),
+        ];
+        for (branch, taken) in cases {
+            let prog = [Instruction::Save(0, false), branch, Instruction::Fail, Instruction::Save(1, true)];
+            assert_eq!(nfa_matches(&prog, ""), taken, "{branch:?}");
+            assert_eq!(reference_matches(&prog, ""), taken, "{branch:?}");
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn search_agrees_with_the_reference(pattern in pattern(), input in "[abc ]{0,8}") {
+            let prog = compile(&pattern).unwrap();
+            prop_assert_eq!(
+                interpreter::search(&prog, &input).is_some(),
+                reference_matches(&prog, &input),
+                "{:?} on {:?}", pattern, input);
+        }
+    }
+}
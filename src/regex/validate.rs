@@ -0,0 +1,185 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::error::RegexError;
+use crate::regex::Instruction;
+
+/// A likely mistake in a program that doesn't stop it from running.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Lint {
+    /// No match instruction can be reached, so the program will never match anything.
+    NoMatch,
+    /// Nothing jumps or falls through to the instruction at `pc`.
+    Unreachable { pc: usize },
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Lint::NoMatch => write!(f, "Program has no reachable match instruction"),
+            Lint::Unreachable { pc } => write!(f, "Instruction {pc} is unreachable"),
+        }
+    }
+}
+
+/// Looks for parts of the program that could never be run.
+pub fn lint(prog: &[Instruction]) -> Vec<Lint> {
+    let reachable = reachable(prog);
+    let mut lints = Vec::new();
+
+    let has_match = prog.iter().zip(&reachable)
+        .any(|(inst, reachable)| *reachable && matches!(inst, Instruction::Save(_, true)));
+    if !has_match {
+        lints.push(Lint::NoMatch);
+    }
+
+    lints.extend(reachable.iter().enumerate()
+        .filter(|(_, reachable)| !**reachable)
+        .map(|(pc, _)| Lint::Unreachable { pc }));
+    lints
+}
+
+/// Checks that the program is well formed enough to ship, which `lint` doesn't insist on.
+///
+/// For now this rejects loops that a thread can go all the way around without consuming any input,
+/// reporting the first instruction of the loop that was found. The interpreter copes with these at
+/// runtime, but they usually mean the program wasn't built the way it was meant to be. Note that
+/// `compile` produces them for patterns like `(a*)*`. Backreferences are treated as consuming,
+/// since they only don't when their group captured nothing.
+pub fn validate(prog: &[Instruction]) -> Result<(), RegexError> {
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    enum State {
+        Unvisited,
+        OnPath,
+        Done,
+    }
+
+    let mut states = vec![State::Unvisited; prog.len()];
+    for root in 0..prog.len() {
+        if states[root] != State::Unvisited {
+            continue;
+        }
+
+        // A depth first search along the epsilon edges, keeping track of which edge to follow next
+        // from each instruction on the current path. Reaching an instruction that's already on the
+        // path means there's a cycle.
+        states[root] = State::OnPath;
+        let mut path = vec![(root, 0)];
+        while let Some(&(pc, edge)) = path.last() {
+            let Some(next) = epsilon_successors(pc, &prog[pc]).get(edge).copied() else {
+                states[pc] = State::Done;
+                path.pop();
+                continue;
+            };
+            path.last_mut().unwrap().1 += 1;
+
+            match next.map(|next| (next, states.get(next))) {
+                Some((next, Some(State::OnPath))) => return Err(RegexError::EpsilonCycle { pc: next }),
+                Some((next, Some(State::Unvisited))) => {
+                    states[next] = State::OnPath;
+                    path.push((next, 0));
+                }
+                // Finished instructions, jumps off the end, and missing second destinations.
+                _ => (),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The instructions that a thread could move to from `pc` without consuming anything.
+fn epsilon_successors(pc: usize, inst: &Instruction) -> [Option<usize>; 2] {
+    match inst {
+        Instruction::Branch{consume: true, ..}
+            | Instruction::Byte(_)
+            | Instruction::ClassBitmap(_)
+            | Instruction::Backref(_) => [None, None],
+        _ => successors(pc, inst),
+    }
+}
+
+/// Marks every instruction that some thread starting at pc 0 could get to.
+fn reachable(prog: &[Instruction]) -> Vec<bool> {
+    let mut reachable = vec![false; prog.len()];
+    let mut to_visit = vec![0];
+    while let Some(pc) = to_visit.pop() {
+        if pc >= prog.len() || reachable[pc] {
+            continue;
+        }
+        reachable[pc] = true;
+        to_visit.extend(successors(pc, &prog[pc]).into_iter().flatten());
+    }
+    reachable
+}
+
+/// The instructions that a thread could move to after running the one at `pc`.
+pub(crate) fn successors(pc: usize, inst: &Instruction) -> [Option<usize>; 2] {
+    match *inst {
+        Instruction::Save(_, true) | Instruction::Fail => [None, None],
+        Instruction::Save(_, false) | Instruction::Backref(_) | Instruction::Assert(_) => [Some(pc + 1), None],
+        Instruction::Branch{consume: true, ..} | Instruction::Byte(_) | Instruction::ClassBitmap(_) => {
+            [Some(pc + 1), None]
+        }
+        // A comparison against every byte is an unconditional jump.
+        Instruction::Branch{c_min: 0, c_max: u8::MAX, dest, consume: false, inverted: false} => {
+            [Some(dest), None]
+        }
+        Instruction::Branch{dest, consume: false, ..} => [Some(dest), Some(pc + 1)],
+        Instruction::Split(dest1, dest2) => [Some(dest1), Some(dest2)],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::regex::compile;
+
+    #[test]
+    fn compiled_programs_have_no_lints() {
+        for pattern in ["abc", "a|b*", "(x+)\\1"] {
+            assert_eq!(lint(&compile(pattern).unwrap()), [], "{pattern:?}");
+        }
+    }
+
+    #[test]
+    fn programs_that_never_reach_a_match_are_linted() {
+        let prog = [Instruction::Save(0, false), Instruction::Fail, Instruction::Save(1, true)];
+        assert_eq!(lint(&prog), [Lint::NoMatch, Lint::Unreachable { pc: 2 }]);
+
+        let prog = [Instruction::Save(0, false), Instruction::Byte(b'a')];
+        assert_eq!(lint(&prog), [Lint::NoMatch]);
+    }
+
+    #[test]
+    fn skipped_instructions_are_unreachable() {
+        let jump = Instruction::Branch { c_min: 0, c_max: u8::MAX, dest: 3, consume: false, inverted: false };
+        let prog = [jump, Instruction::Byte(b'a'), Instruction::Byte(b'b'), Instruction::Save(1, true)];
+        assert_eq!(lint(&prog), [Lint::Unreachable { pc: 1 }, Lint::Unreachable { pc: 2 }]);
+    }
+
+    #[test]
+    fn loops_that_consume_are_valid() {
+        let prog = [
+            Instruction::Split(1, 3),
+            Instruction::Branch { c_min: b'a', c_max: b'a', dest: 0, consume: true, inverted: false },
+            Instruction::Branch { c_min: 0, c_max: u8::MAX, dest: 0, consume: false, inverted: false },
+            Instruction::Save(1, true),
+        ];
+        assert!(validate(&prog).is_ok());
+        assert!(validate(&compile("(ab|c)*d+").unwrap()).is_ok());
+    }
+
+    #[test]
+    fn loops_that_never_consume_are_epsilon_cycles() {
+        let prog = [
+            Instruction::Save(0, false),
+            Instruction::Split(2, 4),
+            Instruction::Branch { c_min: 0, c_max: u8::MAX, dest: 1, consume: false, inverted: false },
+            Instruction::Byte(b'a'),
+            Instruction::Save(1, true),
+        ];
+        let result = validate(&prog);
+        assert!(matches!(result, Err(RegexError::EpsilonCycle { pc: 1 })), "{result:?}");
+    }
+}
@@ -0,0 +1,198 @@
+// The command line tool only exists with the standard library.
+#![cfg(feature = "std")]
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+use regex_demo::regex;
+
+/// Writes `contents` to a file called `name` in a directory of its own for `test`, and returns its
+/// path.
+fn temp_file(test: &str, name: &str, contents: &[u8]) -> String {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(test);
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    fs::write(&path, contents).unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+fn run(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_regex-demo")).args(args).output().unwrap()
+}
+
+/// The lines that were printed after the "N matches in T s" header, which changes from run to run.
+fn results(output: &Output) -> Vec<String> {
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout.clone()).unwrap().lines().skip(1).map(str::to_string).collect()
+}
+
+#[test]
+fn pattern_and_bin_file_find_the_same_lines() {
+    let text = temp_file("pattern_and_bin", "text.txt", b"xaab\nab\nb\nzzaaab\n");
+    let prog = regex::compile("a+b").unwrap();
+    let bin = temp_file("pattern_and_bin", "prog.bin", &regex::bin::encode(&prog).unwrap());
+
+    let from_pattern = results(&run(&["-e", "a+b", &text]));
+    assert_eq!(from_pattern, ["xaab", "ab", "zzaaab"]);
+    assert_eq!(results(&run(&[&bin, &text])), from_pattern);
+}
+
+#[test]
+fn bad_non_ascii_escapes_point_at_the_backslash() {
+    let text = temp_file("bad_escapes", "text.txt", b"\xe2\x82\xac\n");
+    for pattern in ["\\€", "[\\€]", "ab\\€"] {
+        let output = run(&["-e", pattern, &text]);
+        assert_eq!(output.status.code(), Some(1), "{pattern}");
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.contains("unsupported escape sequence"), "{stderr}");
+        let caret = stderr.lines().last().unwrap();
+        let backslash = pattern.find('\\').unwrap();
+        assert_eq!(caret, format!("    {}^", " ".repeat(backslash)), "{pattern}");
+    }
+}
+
+#[cfg(feature = "gzip")]
+#[test]
+fn gzipped_files_give_the_same_matches_as_plain_ones() {
+    use std::io::Write;
+
+    let plain = b"one\nab two\nthree\nzab\n";
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(plain).unwrap();
+    let compressed = encoder.finish().unwrap();
+    let plain_file = temp_file("gzipped_files", "text.txt", plain);
+    let gz_file = temp_file("gzipped_files", "text.txt.gz", &compressed);
+
+    for flags in [&["-n"][..], &["-n", "--binary"]] {
+        let search = |file: &str| results(&run(&[flags, &["-e", "a+b", file]].concat()));
+        assert_eq!(search(&plain_file), ["2:ab two", "4:zab"]);
+        assert_eq!(search(&gz_file), search(&plain_file));
+    }
+}
+
+#[cfg(not(feature = "gzip"))]
+#[test]
+fn binary_searches_gzipped_bytes_without_the_gzip_feature() {
+    let file = temp_file("gzipped_bytes", "text.gz", b"\x1f\x8b\x08\0xyz\nabc\n");
+    assert_eq!(results(&run(&["--binary", "-n", "-e", "abc", &file])), ["2:abc"]);
+
+    // Text mode still refuses the file rather than searching compressed bytes as if they were text.
+    assert!(!run(&["-e", "abc", &file]).status.success());
+}
+
+#[test]
+fn trace_runs_on_lines_without_the_required_literal() {
+    let text = temp_file("trace_literal", "text.txt", b"x\n");
+    let output = run(&["--trace", "-e", "b", &text]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let events: Vec<&str> = stderr.lines().map(|line| line.split_once(":1: ").unwrap().1).collect();
+    assert_eq!(events, [
+        "@0 0: split 3 1 x1 -> split to 3, 1",
+        "@0 3: save 0 x1 -> continue to 4",
+        "@0 1: range 0x00 0xff x1 -> consume",
+        "@0 4: char 'b' x1 -> reject",
+        "@1 2: jump 0 x1 -> continue to 0",
+        "@1 0: split 3 1 x1 -> split to 3, 1",
+        "@1 3: save 0 x1 -> continue to 4",
+        "@1 1: range 0x00 0xff x1 -> reject",
+        "@1 4: char 'b' x1 -> reject",
+    ]);
+}
+
+#[test]
+fn profile_and_stats_run_on_lines_without_the_required_literal() {
+    let text = temp_file("profile_literal", "text.txt", b"xy\n");
+    let profile = String::from_utf8(run(&["--profile", "-e", "b", &text]).stderr).unwrap();
+    assert!(profile.contains(":1: peak of "), "{profile}");
+
+    let stats = results(&run(&["--stats", "-e", "b", &text]));
+    assert_eq!(stats[0], "14 steps, 5 max threads, 2 chars scanned");
+}
+
+#[test]
+fn count_and_count_matches_differ_on_lines_with_several_matches() {
+    let text = temp_file("count_matches", "text.txt", b"ab ab ab\nab\nxx ab ab ab\n");
+    let count = String::from_utf8(run(&["-c", "-e", "ab", &text]).stdout).unwrap();
+    assert!(count.starts_with("3 matches in "), "{count}");
+    assert_eq!(count.lines().count(), 1);
+
+    let output = run(&["--count-matches", "-e", "ab", &text]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "7\n");
+}
+
+/// The count from the "N matches in T s" header.
+fn header_count(output: &Output) -> usize {
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8(output.stdout.clone()).unwrap();
+    stdout.split_once(" matches in ").unwrap().0.parse().unwrap()
+}
+
+#[test]
+fn line_regexp_only_selects_whole_lines() {
+    let text = temp_file("line_regexp", "text.txt", b"abc\nabcd\nxabc\nabc\nzz\n");
+    assert_eq!(results(&run(&["-n", "-x", "-e", "abc", &text])), ["1:abc", "4:abc"]);
+    assert_eq!(results(&run(&["-n", "-xv", "-e", "abc", &text])), ["2:abcd", "3:xabc", "5:zz"]);
+    assert_eq!(header_count(&run(&["-xc", "-e", "abc", &text])), 2);
+    assert_eq!(header_count(&run(&["-xvc", "-e", "abc", &text])), 3);
+    assert_eq!(header_count(&run(&["-xc", "-e", "abcd?", &text])), 3);
+}
+
+#[test]
+fn only_matching_prints_each_match() {
+    let text = temp_file("only_matching", "text.txt", b"none here\nfoo 12 bar 345 baz\n");
+    assert_eq!(results(&run(&["-o", "-e", "[0-9]+", &text])), ["12", "345"]);
+    assert_eq!(results(&run(&["-on", "-e", "[0-9]+", &text])), ["2:12", "2:345"]);
+}
+
+#[test]
+fn files_with_matches_lists_each_matching_file_once() {
+    let first = temp_file("files_with_matches", "first.txt", b"foo\nfoo again\n");
+    let second = temp_file("files_with_matches", "second.txt", b"bar\n");
+    let third = temp_file("files_with_matches", "third.txt", b"bar\nfoo\nfoo\n");
+    let output = run(&["-l", "-e", "foo", &first, &second, &third]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), format!("{first}\n{third}\n"));
+}
+
+#[test]
+fn recursive_searches_nested_files_in_order() {
+    let a = temp_file("recursive", "a.txt", b"foo\n");
+    temp_file("recursive/sub", "b.txt", b"nothing\n");
+    let c = temp_file("recursive/sub/deeper", "c.txt", b"x\nfoo bar\n");
+    let d = temp_file("recursive/sub", "d.txt", b"foofoo\n");
+    let root = PathBuf::from(&a).parent().unwrap().to_str().unwrap().to_string();
+    assert_eq!(
+        results(&run(&["-rn", "-e", "foo", &root])),
+        // Entries are sorted by name, so `d.txt` comes before the `deeper` directory.
+        [format!("{a}:1:foo"), format!("{d}:1:foofoo"), format!("{c}:2:foo bar")]);
+}
+
+#[test]
+fn crlf_lines_match_without_their_carriage_return() {
+    let text = temp_file("crlf", "text.txt", b"xabc\r\nabcd\r\nabc\r\n");
+    for mode in [&[][..], &["--binary"]] {
+        let search = |flags: &[&str]| results(&run(&[mode, flags, &["-e", "b.*$", &text]].concat()));
+        assert_eq!(search(&["-n"]), ["1:xabc", "2:abcd", "3:abc"]);
+        assert_eq!(search(&["-o"]), ["bc", "bcd", "bc"]);
+    }
+    assert_eq!(header_count(&run(&["-c", "-e", "abc$", &text])), 2);
+}
+
+#[test]
+fn a_last_line_without_a_newline_loses_its_carriage_return_too() {
+    let text = temp_file("crlf_last_line", "text.txt", b"abc\r\nxabc\r");
+    for mode in [&[][..], &["--binary"]] {
+        let search = |flags: &[&str]| results(&run(&[mode, flags, &["-e", "abc$", &text]].concat()));
+        assert_eq!(search(&["-n"]), ["1:abc", "2:xabc"]);
+        assert_eq!(search(&["-o"]), ["abc", "abc"]);
+    }
+}
+
+#[test]
+fn column_is_one_based() {
+    let text = temp_file("column", "text.txt", b"abc\ndef\nxxxxxfoo\n");
+    assert_eq!(results(&run(&["-n", "--column", "-e", "foo", &text])), ["3:6:xxxxxfoo"]);
+}